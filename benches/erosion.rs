@@ -0,0 +1,145 @@
+// Times erosion_run on a generated height map, run with cargo bench --bench erosion
+use glam::Vec3;
+use rayon::prelude::*;
+use rust_heightmap::cubemap_data::AtomicF64;
+use rust_heightmap::erosion::erosion_run;
+use rust_heightmap::noise::fbm;
+use rust_heightmap::random::random_1d_to_array;
+use rust_heightmap::{CubeMapDataLayer, CubeMapFace, InterpolatedBiomeData};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const RES: u32 = 512;
+const RADIUS: f64 = 1000000.0;
+const ITERATIONS: u16 = 16;
+const DROPLETS: u16 = 400;
+
+fn layers() -> (
+    CubeMapDataLayer<f64>,
+    CubeMapDataLayer<InterpolatedBiomeData>,
+) {
+    let mut height = CubeMapDataLayer::new(RES, RADIUS);
+    for face in [
+        CubeMapFace::PX,
+        CubeMapFace::PY,
+        CubeMapFace::PZ,
+        CubeMapFace::NX,
+        CubeMapFace::NY,
+        CubeMapFace::NZ,
+    ] {
+        for y in 0..RES as usize {
+            for x in 0..RES as usize {
+                let dir = height.pixel_coords_to_direction(&face, x, y);
+                height.set_pixel(
+                    &face,
+                    x,
+                    y,
+                    RADIUS + fbm(dir * 3.0, 1, 6, 2.0, 0.5) * 8000.0,
+                );
+            }
+        }
+    }
+    let biome = CubeMapDataLayer::new(
+        RES,
+        InterpolatedBiomeData {
+            dominating_id: 0,
+            second_id: 0,
+            second_weight: 0.0,
+            color: Vec3::ONE,
+            roughness: 0.5,
+            erosion_strength: 1.0,
+            deposition_strength: 1.0,
+            craters_probability: 0.0,
+            min_crater_size: 0.0,
+            max_crater_size: 0.0,
+        },
+    );
+    (height, biome)
+}
+
+// 1, 2, 4... up to all the cores, the face mutexes this replaced only showed up with many threads
+fn thread_counts() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let mut counts: Vec<usize> = (0..)
+        .map(|power| 1 << power)
+        .take_while(|count| *count < cores)
+        .collect();
+    counts.push(cores);
+    counts
+}
+
+// The adds of erosion_run alone, ITERATIONS x DROPLETS droplets of 64 steps each adding to the
+// pixel under it, into per face mutexes like before and into atomics like now
+fn contended_adds(threads: usize) {
+    let res = RES as usize;
+    let droplets = ITERATIONS as usize * DROPLETS as usize;
+    let path = |droplet: usize| {
+        let [face, x, y]: [f64; 3] = random_1d_to_array(droplet as f64);
+        let (face, x, y) = (
+            (face * 6.0) as usize % 6,
+            (x * res as f64) as usize,
+            (y * res as f64) as usize,
+        );
+        (0..64).map(move |step| (face, (x + step) % res, (y + step / 2) % res))
+    };
+
+    let mutexes: Vec<Mutex<Vec<f64>>> = (0..6).map(|_| Mutex::new(vec![0.0; res * res])).collect();
+    let start = Instant::now();
+    (0..droplets).into_par_iter().for_each(|droplet| {
+        for (face, x, y) in path(droplet) {
+            mutexes[face].lock().unwrap()[y * res + x] += 0.001;
+        }
+    });
+    let mutex_time = start.elapsed();
+
+    let atomics: Vec<Vec<AtomicF64>> = (0..6)
+        .map(|_| (0..res * res).map(|_| AtomicF64::new(0.0)).collect())
+        .collect();
+    let start = Instant::now();
+    (0..droplets).into_par_iter().for_each(|droplet| {
+        for (face, x, y) in path(droplet) {
+            atomics[face][y * res + x].fetch_add(0.001);
+        }
+    });
+    println!(
+        "adds of {droplets} droplets, {threads} threads: mutexes {mutex_time:?}, atomics {:?}",
+        start.elapsed()
+    );
+}
+
+fn main() {
+    for threads in thread_counts() {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| contended_adds(threads));
+    }
+    for threads in thread_counts() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        for deterministic in [false, true] {
+            let (mut height, mut biome) = layers();
+            let start = Instant::now();
+            pool.install(|| {
+                erosion_run(
+                    &mut height,
+                    &mut biome,
+                    ITERATIONS,
+                    DROPLETS,
+                    RADIUS,
+                    1,
+                    1.0,
+                    1.0,
+                    deterministic,
+                )
+            });
+            println!(
+                "erosion_run res {RES}, {ITERATIONS} x {DROPLETS} droplets, {threads} threads, deterministic {deterministic}: {:?}",
+                start.elapsed()
+            );
+        }
+    }
+}
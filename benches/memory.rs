@@ -0,0 +1,69 @@
+// Peak and retained memory of a generated planet with and without generatorConfig.compactLayers,
+// run with cargo bench --bench memory. Every mode runs in its own process, so the peaks do not mix
+use rust_heightmap::json_input::parse_input_data;
+use rust_heightmap::PlanetGenerator;
+use std::fs;
+use std::process::Command;
+
+const RES: u32 = 512;
+const CHILD_ENV: &str = "PLANETGEN_MEMORY_MODE";
+
+// VmRSS or VmHWM from /proc/self/status, in MiB
+fn memory(field: &str) -> f64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status
+        .lines()
+        .find(|line| line.starts_with(field))
+        .unwrap();
+    let kib: f64 = line
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    kib / 1024.0
+}
+
+fn run(compact: bool) {
+    let mut input = parse_input_data(include_str!("../test-input.json"));
+    input.generator_config.cube_map_resolution = RES;
+    input.generator_config.compact_layers = compact;
+    input.generator_config.erosion_iterations = 2;
+    input.generator_config.erosion_droplets_count = 100;
+    input.generator_config.subdivide_initial = 1;
+    input.generator_config.subdivide_level1 = 1;
+    input.generator_config.subdivide_level2 = 2;
+    input.generator_config.subdivide_level3 = 3;
+    let generator = PlanetGenerator::new(input);
+
+    let planet = generator.generate();
+    let generation_peak = memory("VmHWM:");
+    let retained = memory("VmRSS:");
+
+    // writing 5 resets VmHWM, so the next peak is the one of saving alone
+    fs::write("/proc/self/clear_refs", "5").unwrap();
+    let out_dir = tempfile::tempdir().unwrap();
+    generator.save_to(&planet, out_dir.path());
+    let save_peak = memory("VmHWM:");
+
+    println!(
+        "res {RES}, compact {compact}: generation peak {generation_peak:.0} MiB, kept after generation {retained:.0} MiB, saving peak {save_peak:.0} MiB"
+    );
+}
+
+fn main() {
+    if let Ok(mode) = std::env::var(CHILD_ENV) {
+        run(mode == "compact");
+        return;
+    }
+    for mode in ["full", "compact"] {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .env(CHILD_ENV, mode)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{mode} run failed");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        print!("{}", stdout.lines().last().unwrap_or_default());
+        println!();
+    }
+}
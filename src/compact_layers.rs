@@ -0,0 +1,355 @@
+use crate::cubemap_data::{surface_normal, CubeMapDataLayer, CubeMapFace};
+use crate::generate_terrain::{InterpolatedBiomeData, TerrainLayers, TerrainMaps};
+use crate::json_input::{InputBiome, InputTerrain};
+use glam::{DVec3, Vec3};
+
+// Heights as f32 relative to the radius, 4 bytes per pixel instead of 8. Close to the surface f32
+// still resolves a millimetre or so, while absolute f32 heights of an Earth sized planet would be
+// stuck with half metre steps
+pub struct CompactHeightLayer {
+    pub radius: f64,
+    pub relative: CubeMapDataLayer<f32>,
+}
+
+impl CompactHeightLayer {
+    // Kept in the same storage as the layer
+    pub fn from_layer(height: &CubeMapDataLayer<f64>, radius: f64) -> CompactHeightLayer {
+        let mut relative = CubeMapDataLayer::new_in(height.res, 0.0, height.storage());
+        relative.par_zip_pixels(height, |_, _, _, _, height| (height - radius) as f32);
+        CompactHeightLayer { radius, relative }
+    }
+
+    pub fn to_layer(&self) -> CubeMapDataLayer<f64> {
+        let mut height = CubeMapDataLayer::new_in(self.relative.res, 0.0, self.relative.storage());
+        height.par_zip_pixels(&self.relative, |_, _, _, _, relative| {
+            self.radius + *relative as f64
+        });
+        height
+    }
+
+    pub fn get_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> f64 {
+        self.radius + self.relative.get_pixel(face, x, y) as f64
+    }
+
+    pub fn get_bilinear(&self, dir: DVec3) -> f64 {
+        self.radius + self.relative.get_bilinear(dir)
+    }
+
+    pub fn get_normal(&self, dir: DVec3, dxrange: f64) -> DVec3 {
+        surface_normal(dir, dxrange, |dir| self.get_bilinear(dir))
+    }
+}
+
+// Biome data in 18 bytes instead of 44. The ids stay as they are, the weight, color and roughness
+// get the same 8 bits the saved maps and the vertices have, the biome parameters 16 bits over the
+// range they have in the input biomes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactBiomeData {
+    pub dominating_id: u8,
+    pub second_id: u8,
+    pub second_weight: u8,
+    pub roughness: u8,
+    pub color: [u8; 3],
+    // erosion strength, deposition strength, craters probability, min and max crater size
+    pub parameters: [u16; 5],
+}
+
+// Every biome parameter of a pixel is a weighted average of the input biomes, so it always lies
+// between the smallest and the biggest value of the biomes
+#[derive(Clone, Debug, PartialEq)]
+pub struct BiomeQuantization {
+    ranges: [(f32, f32); 5],
+}
+
+fn encode_unorm8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn decode_unorm8(value: u8) -> f32 {
+    value as f32 / 255.0
+}
+
+impl BiomeQuantization {
+    pub fn new(terrain: &InputTerrain) -> BiomeQuantization {
+        let range = |parameter: fn(&InputBiome) -> f64| {
+            terrain
+                .biomes
+                .iter()
+                .map(|biome| parameter(biome) as f32)
+                .fold((f32::MAX, f32::MIN), |(min, max), value| {
+                    (min.min(value), max.max(value))
+                })
+        };
+        BiomeQuantization {
+            ranges: [
+                range(|biome| biome.erosion_strength),
+                range(|biome| biome.deposition_strength),
+                range(|biome| biome.craters_probability),
+                range(|biome| biome.min_crater_size),
+                range(|biome| biome.max_crater_size),
+            ],
+        }
+    }
+
+    // Biggest error a decoded parameter can have, in the units of the parameter
+    pub fn max_error(&self, parameter: usize) -> f32 {
+        let (min, max) = self.ranges[parameter];
+        (max - min).max(0.0) / 65535.0 / 2.0
+    }
+
+    fn encode_parameter(&self, parameter: usize, value: f32) -> u16 {
+        let (min, max) = self.ranges[parameter];
+        if max <= min {
+            return 0;
+        }
+        ((value - min) / (max - min) * 65535.0)
+            .clamp(0.0, 65535.0)
+            .round() as u16
+    }
+
+    fn decode_parameter(&self, parameter: usize, value: u16) -> f32 {
+        let (min, max) = self.ranges[parameter];
+        if max <= min {
+            return min;
+        }
+        min + value as f32 / 65535.0 * (max - min)
+    }
+
+    pub fn encode(&self, biome: &InterpolatedBiomeData) -> CompactBiomeData {
+        let parameters = [
+            biome.erosion_strength,
+            biome.deposition_strength,
+            biome.craters_probability,
+            biome.min_crater_size,
+            biome.max_crater_size,
+        ];
+        CompactBiomeData {
+            dominating_id: biome.dominating_id,
+            second_id: biome.second_id,
+            second_weight: encode_unorm8(biome.second_weight),
+            roughness: encode_unorm8(biome.roughness),
+            color: biome.color.to_array().map(encode_unorm8),
+            parameters: std::array::from_fn(|index| {
+                self.encode_parameter(index, parameters[index])
+            }),
+        }
+    }
+
+    pub fn decode(&self, biome: &CompactBiomeData) -> InterpolatedBiomeData {
+        let parameters: [f32; 5] =
+            std::array::from_fn(|index| self.decode_parameter(index, biome.parameters[index]));
+        InterpolatedBiomeData {
+            dominating_id: biome.dominating_id,
+            second_id: biome.second_id,
+            second_weight: decode_unorm8(biome.second_weight),
+            color: Vec3::from_array(biome.color.map(decode_unorm8)),
+            roughness: decode_unorm8(biome.roughness),
+            erosion_strength: parameters[0],
+            deposition_strength: parameters[1],
+            craters_probability: parameters[2],
+            min_crater_size: parameters[3],
+            max_crater_size: parameters[4],
+        }
+    }
+}
+
+// TerrainLayers in 22 bytes per pixel instead of 52, for keeping finished planets around. The
+// stages keep working on the full layers, erosion adds up changes far below what f32 can hold
+pub struct CompactTerrainLayers {
+    pub height: CompactHeightLayer,
+    pub biome: CubeMapDataLayer<CompactBiomeData>,
+    pub quantization: BiomeQuantization,
+    // already 8 bits, kept as they are
+    pub splat: Vec<CubeMapDataLayer<[u8; 4]>>,
+}
+
+fn copy_splat(splat: &[CubeMapDataLayer<[u8; 4]>]) -> Vec<CubeMapDataLayer<[u8; 4]>> {
+    splat
+        .iter()
+        .map(|layer| {
+            let mut copy = CubeMapDataLayer::new_in(layer.res, [0; 4], layer.storage());
+            copy.par_zip_pixels(layer, |_, _, _, _, weights| *weights);
+            copy
+        })
+        .collect()
+}
+
+impl CompactTerrainLayers {
+    pub fn from_layers(terrain: &InputTerrain, layers: &TerrainLayers) -> CompactTerrainLayers {
+        let quantization = BiomeQuantization::new(terrain);
+        let mut biome = CubeMapDataLayer::new_in(
+            layers.biome.res,
+            CompactBiomeData::default(),
+            layers.biome.storage(),
+        );
+        biome.par_zip_pixels(&layers.biome, |_, _, _, _, data| quantization.encode(data));
+        CompactTerrainLayers {
+            height: CompactHeightLayer::from_layer(&layers.height, terrain.radius),
+            biome,
+            quantization,
+            splat: copy_splat(&layers.splat),
+        }
+    }
+
+    pub fn to_layers(&self) -> TerrainLayers {
+        let mut biome = CubeMapDataLayer::new_in(
+            self.biome.res,
+            self.quantization.decode(&CompactBiomeData::default()),
+            self.biome.storage(),
+        );
+        biome.par_zip_pixels(&self.biome, |_, _, _, _, data| {
+            self.quantization.decode(data)
+        });
+        TerrainLayers {
+            height: self.height.to_layer(),
+            biome,
+            splat: copy_splat(&self.splat),
+        }
+    }
+
+    pub fn get_biome_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> InterpolatedBiomeData {
+        self.quantization.decode(&self.biome.get_pixel(face, x, y))
+    }
+
+    pub fn get_biome(&self, dir: DVec3) -> InterpolatedBiomeData {
+        self.quantization.decode(&self.biome.get(dir))
+    }
+}
+
+impl TerrainMaps for CompactTerrainLayers {
+    fn res(&self) -> u32 {
+        self.height.relative.res
+    }
+
+    fn height_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> f64 {
+        self.height.get_pixel(face, x, y)
+    }
+
+    fn height_bilinear(&self, dir: DVec3) -> f64 {
+        self.height.get_bilinear(dir)
+    }
+
+    fn height_normal(&self, dir: DVec3) -> DVec3 {
+        self.height
+            .get_normal(dir, self.height.relative.get_pixel_distance_for_dir(dir))
+    }
+
+    fn biome(&self, dir: DVec3) -> InterpolatedBiomeData {
+        self.get_biome(dir)
+    }
+
+    fn biome_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> InterpolatedBiomeData {
+        self.get_biome_pixel(face, x, y)
+    }
+
+    fn splat(&self) -> &[CubeMapDataLayer<[u8; 4]>] {
+        &self.splat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_terrain::generate_terrain_layers;
+    use crate::json_input::{parse_input_data, InputTerrainStage};
+    use std::mem::size_of;
+
+    #[test]
+    fn test_compact_accuracy() {
+        assert_eq!(size_of::<CompactBiomeData>(), 18);
+        assert_eq!(size_of::<InterpolatedBiomeData>(), 44);
+
+        // Earth radius with heights within 127 km
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 32;
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.stages = Some(vec![InputTerrainStage::Height, InputTerrainStage::Biomes]);
+        let terrain = input.terrain.as_ref().unwrap();
+        let layers = generate_terrain_layers(&input, terrain);
+        let compact = CompactTerrainLayers::from_layers(terrain, &layers);
+        let restored = compact.to_layers();
+
+        let mut absolute_f32_error = 0.0_f64;
+        for face in &[CubeMapFace::PX, CubeMapFace::NY, CubeMapFace::NZ] {
+            for y in 0..32 {
+                for x in 0..32 {
+                    // half of the f32 step at 127 km
+                    let height = layers.height.get_pixel(face, x, y);
+                    assert!((restored.height.get_pixel(face, x, y) - height).abs() < 0.004);
+                    absolute_f32_error =
+                        absolute_f32_error.max((height as f32 as f64 - height).abs());
+                    let dir =
+                        (layers.height.pixel_coords_to_direction(face, x, y) + 0.01).normalize();
+                    assert!(
+                        (compact.height.get_bilinear(dir) - layers.height.get_bilinear(dir)).abs()
+                            < 0.004
+                    );
+
+                    let biome = layers.biome.get_pixel(face, x, y);
+                    let decoded = compact.get_biome_pixel(face, x, y);
+                    assert_eq!(decoded.dominating_id, biome.dominating_id);
+                    assert_eq!(decoded.second_id, biome.second_id);
+                    assert!(
+                        (decoded.color - biome.color).abs().max_element() <= 0.5 / 255.0 + 1e-6
+                    );
+                    assert!(
+                        (decoded.second_weight - biome.second_weight).abs() <= 0.5 / 255.0 + 1e-6
+                    );
+                    let parameters = [
+                        (decoded.erosion_strength, biome.erosion_strength),
+                        (decoded.deposition_strength, biome.deposition_strength),
+                        (decoded.craters_probability, biome.craters_probability),
+                        (decoded.min_crater_size, biome.min_crater_size),
+                        (decoded.max_crater_size, biome.max_crater_size),
+                    ];
+                    for (index, (decoded, original)) in parameters.into_iter().enumerate() {
+                        // plus the f32 rounding the interpolated values already have
+                        let bound = compact.quantization.max_error(index) + original.abs() * 1e-6;
+                        assert!(
+                            (decoded - original).abs() <= bound,
+                            "{index}: {decoded} {original}"
+                        );
+                    }
+                }
+            }
+        }
+        // what plain f32 heights would lose
+        assert!(absolute_f32_error > 0.1);
+    }
+
+    #[test]
+    fn test_compact_maps_match_the_full_layers() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 16;
+        input.generator_config.export_splat_maps = true;
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.stages = Some(vec![InputTerrainStage::Height, InputTerrainStage::Biomes]);
+        let terrain = input.terrain.as_ref().unwrap();
+        let layers = generate_terrain_layers(&input, terrain);
+        let compact = CompactTerrainLayers::from_layers(terrain, &layers);
+        let (full, compact): (&dyn TerrainMaps, &dyn TerrainMaps) = (&layers, &compact);
+
+        assert_eq!(compact.res(), full.res());
+        assert_eq!(compact.splat().len(), full.splat().len());
+        for face in &[CubeMapFace::PX, CubeMapFace::NZ] {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let dir = layers.height.pixel_coords_to_direction(face, x, y);
+                    assert!(
+                        (compact.height_pixel(face, x, y) - full.height_pixel(face, x, y)).abs()
+                            < 0.004
+                    );
+                    assert!(compact.height_normal(dir).distance(full.height_normal(dir)) < 1e-3);
+                    assert_eq!(
+                        compact.biome(dir).dominating_id,
+                        full.biome(dir).dominating_id
+                    );
+                    assert_eq!(
+                        compact.splat()[0].get_pixel(face, x, y),
+                        full.splat()[0].get_pixel(face, x, y)
+                    );
+                }
+            }
+        }
+    }
+}
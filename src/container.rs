@@ -0,0 +1,726 @@
+/*
+Planet container layout, everything little endian, strings are u16 length + utf8 bytes:
+    magic: "PGEN"
+    format_version: u32
+    radius: f64
+    min_height: f64
+    max_height: f64
+    resolution: u32
+    seed: f64
+    vertex layout count: u8, then per layout:
+        name: string
+        stride: u16
+        attribute count: u8, then per attribute:
+            name: string, format: u8, components: u8, offset: u16, scale: f32
+    chunk count: u32, then per chunk:
+        name: string, kind: u8, compression: u8,
+        offset: u64 (from the start of the file), length: u64, uncompressed_length: u64
+    chunk data
+
+Height chunks hold resolution^2 f32 values relative to the radius.
+Biome color chunks hold resolution^2 rgba u8 values, alpha is the roughness.
+Biome id chunks hold resolution^2 (dominating_id: u8, second_id: u8, second_weight: u8) records.
+Mesh chunks hold vertex records in the layout named like the chunk kind.
+Mesh center chunks hold (index_main: u32, index: u32, x: f64, y: f64, z: f64) records.
+*/
+use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
+use crate::generate_icosphere::{
+    terrain_vertex_layout, water_vertex_layout, IcosphereChunk, VertexAttribute,
+    VertexAttributeFormat, VertexLayout,
+};
+use crate::json_input::InputCelestialBodyDefinition;
+use crate::planet_generator::{GeneratedPlanet, GeneratedTerrain};
+use rayon::iter::ParallelIterator;
+use rayon::prelude::IntoParallelIterator;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const CONTAINER_MAGIC: &[u8; 4] = b"PGEN";
+pub const CONTAINER_FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkKind {
+    HeightFace,
+    BiomeColorFace,
+    TerrainMesh,
+    WaterMesh,
+    MeshCenters,
+    BiomeIdFace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkCompression {
+    None,
+    Brotli,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainerHeader {
+    pub format_version: u32,
+    pub radius: f64,
+    pub min_height: f64,
+    pub max_height: f64,
+    pub resolution: u32,
+    pub seed: f64,
+    pub vertex_layouts: Vec<VertexLayout>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChunkEntry {
+    pub name: String,
+    pub kind: ChunkKind,
+    pub compression: ChunkCompression,
+    pub offset: u64,
+    pub length: u64,
+    pub uncompressed_length: u64,
+}
+
+// Borrowed data is only read while writing, so the mesh buffers are not copied into the writer
+struct PendingChunk<'a> {
+    name: String,
+    kind: ChunkKind,
+    compression: ChunkCompression,
+    data: Cow<'a, [u8]>,
+}
+
+pub struct ContainerWriter<'a> {
+    header: ContainerHeader,
+    chunks: Vec<PendingChunk<'a>>,
+}
+
+// Random access reader, only the header and the chunk table are read on open
+pub struct PlanetContainer {
+    path: PathBuf,
+    pub header: ContainerHeader,
+    pub chunks: Vec<ChunkEntry>,
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl ChunkKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            ChunkKind::HeightFace => 0,
+            ChunkKind::BiomeColorFace => 1,
+            ChunkKind::TerrainMesh => 2,
+            ChunkKind::WaterMesh => 3,
+            ChunkKind::MeshCenters => 4,
+            ChunkKind::BiomeIdFace => 5,
+        }
+    }
+
+    fn from_u8(value: u8) -> std::io::Result<ChunkKind> {
+        match value {
+            0 => Ok(ChunkKind::HeightFace),
+            1 => Ok(ChunkKind::BiomeColorFace),
+            2 => Ok(ChunkKind::TerrainMesh),
+            3 => Ok(ChunkKind::WaterMesh),
+            4 => Ok(ChunkKind::MeshCenters),
+            5 => Ok(ChunkKind::BiomeIdFace),
+            _ => Err(invalid_data(format!("Unknown chunk kind {}", value))),
+        }
+    }
+}
+
+impl ChunkCompression {
+    fn to_u8(self) -> u8 {
+        match self {
+            ChunkCompression::None => 0,
+            ChunkCompression::Brotli => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> std::io::Result<ChunkCompression> {
+        match value {
+            0 => Ok(ChunkCompression::None),
+            1 => Ok(ChunkCompression::Brotli),
+            _ => Err(invalid_data(format!("Unknown chunk compression {}", value))),
+        }
+    }
+}
+
+impl VertexAttributeFormat {
+    fn to_u8(self) -> u8 {
+        match self {
+            VertexAttributeFormat::Float32 => 0,
+            VertexAttributeFormat::Uint8 => 1,
+            VertexAttributeFormat::Uint16 => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> std::io::Result<VertexAttributeFormat> {
+        match value {
+            0 => Ok(VertexAttributeFormat::Float32),
+            1 => Ok(VertexAttributeFormat::Uint8),
+            2 => Ok(VertexAttributeFormat::Uint16),
+            _ => Err(invalid_data(format!("Unknown vertex format {}", value))),
+        }
+    }
+}
+
+fn write_string(output: &mut Vec<u8>, value: &str) {
+    output.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    output.extend_from_slice(value.as_bytes());
+}
+
+fn read_bytes<const COUNT: usize>(input: &mut dyn Read) -> std::io::Result<[u8; COUNT]> {
+    let mut bytes = [0_u8; COUNT];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(input: &mut dyn Read) -> std::io::Result<u8> {
+    Ok(read_bytes::<1>(input)?[0])
+}
+
+fn read_u16(input: &mut dyn Read) -> std::io::Result<u16> {
+    Ok(u16::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_u32(input: &mut dyn Read) -> std::io::Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_u64(input: &mut dyn Read) -> std::io::Result<u64> {
+    Ok(u64::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_f32(input: &mut dyn Read) -> std::io::Result<f32> {
+    Ok(f32::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_f64(input: &mut dyn Read) -> std::io::Result<f64> {
+    Ok(f64::from_le_bytes(read_bytes(input)?))
+}
+
+fn read_string(input: &mut dyn Read) -> std::io::Result<String> {
+    let mut bytes = vec![0_u8; read_u16(input)? as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| invalid_data(format!("Invalid string: {}", e)))
+}
+
+fn write_header(output: &mut Vec<u8>, header: &ContainerHeader) {
+    output.extend_from_slice(CONTAINER_MAGIC);
+    output.extend_from_slice(&header.format_version.to_le_bytes());
+    output.extend_from_slice(&header.radius.to_le_bytes());
+    output.extend_from_slice(&header.min_height.to_le_bytes());
+    output.extend_from_slice(&header.max_height.to_le_bytes());
+    output.extend_from_slice(&header.resolution.to_le_bytes());
+    output.extend_from_slice(&header.seed.to_le_bytes());
+
+    output.push(header.vertex_layouts.len() as u8);
+    header.vertex_layouts.iter().for_each(|layout| {
+        write_string(output, &layout.name);
+        output.extend_from_slice(&layout.stride.to_le_bytes());
+        output.push(layout.attributes.len() as u8);
+        layout.attributes.iter().for_each(|attribute| {
+            write_string(output, &attribute.name);
+            output.push(attribute.format.to_u8());
+            output.push(attribute.components);
+            output.extend_from_slice(&attribute.offset.to_le_bytes());
+            output.extend_from_slice(&attribute.scale.to_le_bytes());
+        });
+    });
+}
+
+fn read_header(input: &mut dyn Read) -> std::io::Result<ContainerHeader> {
+    let magic: [u8; 4] = read_bytes(input)?;
+    if &magic != CONTAINER_MAGIC {
+        return Err(invalid_data("Not a planet container".to_string()));
+    }
+    let format_version = read_u32(input)?;
+    if format_version != CONTAINER_FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported container format version {}, expected {}",
+            format_version, CONTAINER_FORMAT_VERSION
+        )));
+    }
+    let radius = read_f64(input)?;
+    let min_height = read_f64(input)?;
+    let max_height = read_f64(input)?;
+    let resolution = read_u32(input)?;
+    let seed = read_f64(input)?;
+
+    let layout_count = read_u8(input)?;
+    let mut vertex_layouts = vec![];
+    for _ in 0..layout_count {
+        let name = read_string(input)?;
+        let stride = read_u16(input)?;
+        let attribute_count = read_u8(input)?;
+        let mut attributes = vec![];
+        for _ in 0..attribute_count {
+            attributes.push(VertexAttribute {
+                name: read_string(input)?,
+                format: VertexAttributeFormat::from_u8(read_u8(input)?)?,
+                components: read_u8(input)?,
+                offset: read_u16(input)?,
+                scale: read_f32(input)?,
+            });
+        }
+        vertex_layouts.push(VertexLayout {
+            name,
+            stride,
+            attributes,
+        });
+    }
+
+    Ok(ContainerHeader {
+        format_version,
+        radius,
+        min_height,
+        max_height,
+        resolution,
+        seed,
+        vertex_layouts,
+    })
+}
+
+impl<'a> ContainerWriter<'a> {
+    pub fn new(header: ContainerHeader) -> ContainerWriter<'a> {
+        ContainerWriter {
+            header,
+            chunks: vec![],
+        }
+    }
+
+    pub fn add_chunk(
+        &mut self,
+        name: &str,
+        kind: ChunkKind,
+        compression: ChunkCompression,
+        data: impl Into<Cow<'a, [u8]>>,
+    ) {
+        self.chunks.push(PendingChunk {
+            name: name.to_string(),
+            kind,
+            compression,
+            data: data.into(),
+        });
+    }
+
+    pub fn write(self, path: &Path) -> std::io::Result<()> {
+        let header = self.header;
+        // the data is swapped for the stored bytes, next to the uncompressed length
+        let compressed: Vec<(PendingChunk, u64)> = self
+            .chunks
+            .into_par_iter()
+            .map(|mut chunk| {
+                let uncompressed_length = chunk.data.len() as u64;
+                match chunk.compression {
+                    ChunkCompression::None => {}
+                    ChunkCompression::Brotli => {
+                        let mut output = vec![];
+                        {
+                            let mut writer =
+                                brotli::CompressorWriter::new(&mut output, 40960, 11, 21);
+                            writer.write_all(&chunk.data).expect("Write failed");
+                        }
+                        chunk.data = Cow::Owned(output);
+                    }
+                }
+                (chunk, uncompressed_length)
+            })
+            .collect();
+
+        let mut table = vec![];
+        write_header(&mut table, &header);
+        table.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        // offset + length + uncompressed_length + kind + compression + string length prefix
+        let table_length = table.len()
+            + compressed
+                .iter()
+                .map(|(chunk, _)| 8 * 3 + 2 + 2 + chunk.name.len())
+                .sum::<usize>();
+
+        let mut offset = table_length as u64;
+        compressed.iter().for_each(|(chunk, uncompressed_length)| {
+            write_string(&mut table, &chunk.name);
+            table.push(chunk.kind.to_u8());
+            table.push(chunk.compression.to_u8());
+            table.extend_from_slice(&offset.to_le_bytes());
+            table.extend_from_slice(&(chunk.data.len() as u64).to_le_bytes());
+            table.extend_from_slice(&uncompressed_length.to_le_bytes());
+            offset += chunk.data.len() as u64;
+        });
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&table)?;
+        for (chunk, _) in compressed.iter() {
+            file.write_all(&chunk.data)?;
+        }
+        file.flush()
+    }
+}
+
+impl PlanetContainer {
+    pub fn open(path: &Path) -> std::io::Result<PlanetContainer> {
+        let file = File::open(path)?;
+        let file_length = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+
+        let header = read_header(&mut file)?;
+
+        let chunk_count = read_u32(&mut file)?;
+        let mut chunks = vec![];
+        for _ in 0..chunk_count {
+            let chunk = ChunkEntry {
+                name: read_string(&mut file)?,
+                kind: ChunkKind::from_u8(read_u8(&mut file)?)?,
+                compression: ChunkCompression::from_u8(read_u8(&mut file)?)?,
+                offset: read_u64(&mut file)?,
+                length: read_u64(&mut file)?,
+                uncompressed_length: read_u64(&mut file)?,
+            };
+            if chunk
+                .offset
+                .checked_add(chunk.length)
+                .is_none_or(|end| end > file_length)
+            {
+                return Err(invalid_data(format!(
+                    "Chunk {} is outside of the file",
+                    chunk.name
+                )));
+            }
+            chunks.push(chunk);
+        }
+
+        Ok(PlanetContainer {
+            path: path.to_path_buf(),
+            header,
+            chunks,
+        })
+    }
+
+    pub fn find_chunk(&self, name: &str) -> Option<&ChunkEntry> {
+        self.chunks.iter().find(|chunk| chunk.name == name)
+    }
+
+    pub fn vertex_layout(&self, name: &str) -> Option<&VertexLayout> {
+        self.header
+            .vertex_layouts
+            .iter()
+            .find(|layout| layout.name == name)
+    }
+
+    // Decompressed chunk data
+    pub fn read_chunk(&self, name: &str) -> std::io::Result<Vec<u8>> {
+        let chunk = self
+            .find_chunk(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No chunk {}", name)))?;
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        let mut stored = vec![0_u8; chunk.length as usize];
+        file.read_exact(&mut stored)?;
+
+        let data = match chunk.compression {
+            ChunkCompression::None => stored,
+            ChunkCompression::Brotli => {
+                let mut data = vec![];
+                brotli::Decompressor::new(stored.as_slice(), 40960).read_to_end(&mut data)?;
+                data
+            }
+        };
+        if data.len() as u64 != chunk.uncompressed_length {
+            return Err(invalid_data(format!(
+                "Chunk {} has {} bytes, expected {}",
+                name,
+                data.len(),
+                chunk.uncompressed_length
+            )));
+        }
+        Ok(data)
+    }
+
+    pub fn read_height_layer(&self) -> std::io::Result<CubeMapDataLayer<f64>> {
+        let res = self.header.resolution;
+        let res_usize = res as usize;
+        let faces = [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ];
+
+        // the resolution comes from the file, so it has to agree with the chunks before the layer
+        // is allocated
+        let face_length = (res as u64 * res as u64).checked_mul(4);
+        for face in &faces {
+            let name = format!("height/{}", face);
+            let chunk = self
+                .find_chunk(&name)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No chunk {}", name)))?;
+            if res == 0 || Some(chunk.uncompressed_length) != face_length {
+                return Err(invalid_data(format!(
+                    "Height face {} has {} bytes, which does not match resolution {}",
+                    face, chunk.uncompressed_length, res
+                )));
+            }
+        }
+
+        let mut cube_map_height = CubeMapDataLayer::new(res, 0.0);
+        for face in faces {
+            let bytes = self.read_chunk(&format!("height/{}", face))?;
+            if bytes.len() != res_usize * res_usize * 4 {
+                return Err(invalid_data(format!(
+                    "Height face {} has {} bytes, expected {}",
+                    face,
+                    bytes.len(),
+                    res_usize * res_usize * 4
+                )));
+            }
+            bytes.chunks_exact(4).enumerate().for_each(|(i, value)| {
+                cube_map_height.set_pixel(
+                    &face,
+                    i % res_usize,
+                    i / res_usize,
+                    f32::from_le_bytes(value.try_into().unwrap()) as f64 + self.header.radius,
+                );
+            });
+        }
+
+        Ok(cube_map_height)
+    }
+}
+
+fn add_mesh_chunks<'a>(
+    writer: &mut ContainerWriter<'a>,
+    prefix: &str,
+    kind: ChunkKind,
+    chunks: &'a [IcosphereChunk],
+) {
+    let mut centers = vec![];
+    chunks.iter().for_each(|chunk| {
+        centers.extend_from_slice(&(chunk.index_main as u32).to_le_bytes());
+        centers.extend_from_slice(&(chunk.index as u32).to_le_bytes());
+        centers.extend_from_slice(&chunk.center.x.to_le_bytes());
+        centers.extend_from_slice(&chunk.center.y.to_le_bytes());
+        centers.extend_from_slice(&chunk.center.z.to_le_bytes());
+
+        let name = format!("{}/{}-{}", prefix, chunk.index_main, chunk.index);
+        for (level, data) in [(1, &chunk.level1), (2, &chunk.level2), (3, &chunk.level3)] {
+            writer.add_chunk(
+                &format!("{}.l{}", name, level),
+                kind,
+                ChunkCompression::Brotli,
+                data,
+            );
+        }
+    });
+    writer.add_chunk(
+        &format!("{}/centers", prefix),
+        ChunkKind::MeshCenters,
+        ChunkCompression::None,
+        centers,
+    );
+}
+
+pub fn save_planet_container(
+    path: &Path,
+    input: &InputCelestialBodyDefinition,
+    planet: &GeneratedPlanet,
+) -> std::io::Result<()> {
+    let terrain = input.terrain.as_ref();
+    let mut writer = ContainerWriter::new(ContainerHeader {
+        format_version: CONTAINER_FORMAT_VERSION,
+        radius: terrain.map_or(0.0, |t| t.radius),
+        min_height: terrain.map_or(0.0, |t| t.min_height),
+        max_height: terrain.map_or(0.0, |t| t.max_height),
+        resolution: input.generator_config.cube_map_resolution,
+        seed: terrain.map_or(0.0, |t| t.terrain_generation.seed),
+        vertex_layouts: vec![terrain_vertex_layout(), water_vertex_layout()],
+    });
+
+    if let (Some(terrain), Some(generated)) = (terrain, &planet.terrain) {
+        // relative height, color and roughness, ids and weight of a pixel. The compact layers
+        // already hold these bytes, so they are written without unpacking them
+        let pixel = |face: &CubeMapFace, x: usize, y: usize| match generated {
+            GeneratedTerrain::Full(layers) => {
+                let biome = layers.biome.get_pixel(face, x, y);
+                (
+                    (layers.height.get_pixel(face, x, y) - terrain.radius) as f32,
+                    [
+                        (biome.color.x * 255.0) as u8,
+                        (biome.color.y * 255.0) as u8,
+                        (biome.color.z * 255.0) as u8,
+                        (biome.roughness * 255.0) as u8,
+                    ],
+                    [
+                        biome.dominating_id,
+                        biome.second_id,
+                        (biome.second_weight * 255.0) as u8,
+                    ],
+                )
+            }
+            GeneratedTerrain::Compact(compact) => {
+                let biome = compact.biome.get_pixel(face, x, y);
+                (
+                    compact.height.relative.get_pixel(face, x, y),
+                    [
+                        biome.color[0],
+                        biome.color[1],
+                        biome.color[2],
+                        biome.roughness,
+                    ],
+                    [biome.dominating_id, biome.second_id, biome.second_weight],
+                )
+            }
+        };
+        let res_usize = match generated {
+            GeneratedTerrain::Full(layers) => layers.height.res,
+            GeneratedTerrain::Compact(compact) => compact.height.relative.res,
+        } as usize;
+        for face in [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ] {
+            let mut height = Vec::with_capacity(res_usize * res_usize * 4);
+            let mut color = Vec::with_capacity(res_usize * res_usize * 4);
+            let mut biome_id = Vec::with_capacity(res_usize * res_usize * 3);
+            for y in 0..res_usize {
+                for x in 0..res_usize {
+                    let (value, biome_color, biome_ids) = pixel(&face, x, y);
+                    height.extend_from_slice(&value.to_le_bytes());
+                    color.extend_from_slice(&biome_color);
+                    biome_id.extend_from_slice(&biome_ids);
+                }
+            }
+            writer.add_chunk(
+                &format!("height/{}", face),
+                ChunkKind::HeightFace,
+                ChunkCompression::Brotli,
+                height,
+            );
+            writer.add_chunk(
+                &format!("biome_color/{}", face),
+                ChunkKind::BiomeColorFace,
+                ChunkCompression::Brotli,
+                color,
+            );
+            writer.add_chunk(
+                &format!("biome_id/{}", face),
+                ChunkKind::BiomeIdFace,
+                ChunkCompression::Brotli,
+                biome_id,
+            );
+        }
+        add_mesh_chunks(
+            &mut writer,
+            "terrain",
+            ChunkKind::TerrainMesh,
+            &planet.terrain_icosphere,
+        );
+    }
+
+    if planet.water.is_some() {
+        add_mesh_chunks(
+            &mut writer,
+            "water",
+            ChunkKind::WaterMesh,
+            &planet.water_icosphere,
+        );
+    }
+
+    writer.write(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_container_round_trip() {
+        const RES: u32 = 8;
+        let radius = 6378000.0;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, radius);
+        height.set_pixel(&CubeMapFace::NY, 3, 5, radius + 1234.5);
+
+        let header = ContainerHeader {
+            format_version: CONTAINER_FORMAT_VERSION,
+            radius,
+            min_height: -1000.0,
+            max_height: 2000.0,
+            resolution: RES as u32,
+            seed: 42.0,
+            vertex_layouts: vec![terrain_vertex_layout(), water_vertex_layout()],
+        };
+        let mesh = vec![5_u8; 100];
+        let mut writer = ContainerWriter::new(header.clone());
+        for face in [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ] {
+            let mut data = vec![];
+            for y in 0..RES as usize {
+                for x in 0..RES as usize {
+                    let value = height.get_pixel(&face, x, y) - radius;
+                    data.extend_from_slice(&(value as f32).to_le_bytes());
+                }
+            }
+            writer.add_chunk(
+                &format!("height/{}", face),
+                ChunkKind::HeightFace,
+                ChunkCompression::Brotli,
+                data,
+            );
+        }
+        writer.add_chunk(
+            "water/0-0.l1",
+            ChunkKind::WaterMesh,
+            ChunkCompression::None,
+            vec![1, 2, 3, 4],
+        );
+        // borrowed like the icosphere meshes
+        writer.add_chunk(
+            "terrain/0-0.l1",
+            ChunkKind::TerrainMesh,
+            ChunkCompression::Brotli,
+            &mesh,
+        );
+
+        let path = env::temp_dir().join(format!("planetgen-container-{}.pgen", std::process::id()));
+        writer.write(&path).unwrap();
+
+        let container = PlanetContainer::open(&path).unwrap();
+        assert_eq!(container.header, header);
+        assert_eq!(container.vertex_layout("water").unwrap().stride, 16);
+        assert_eq!(
+            container.read_chunk("water/0-0.l1").unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(container.read_chunk("terrain/0-0.l1").unwrap(), mesh);
+        let loaded = container.read_height_layer().unwrap();
+        assert_eq!(loaded.get_pixel(&CubeMapFace::NY, 3, 5), radius + 1234.5);
+        assert_eq!(loaded.get_pixel(&CubeMapFace::PX, 3, 5), radius);
+
+        // a corrupt resolution is caught before the layer is allocated
+        let mut corrupt = PlanetContainer::open(&path).unwrap();
+        corrupt.header.resolution = u32::MAX;
+        let error = corrupt.read_height_layer().err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // anything else is rejected instead of guessed
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4] = 99;
+        fs::write(&path, &bytes).unwrap();
+        let error = PlanetContainer::open(&path).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,36 +1,42 @@
-use crate::cubemap_data::CubeMapDataLayer;
+use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
 use crate::generate_terrain::InterpolatedBiomeData;
 use crate::math_util::map;
-use crate::random::{random_1d_to_1d, random_1d_to_3d};
+use crate::random::{derive_seed, random_seed_to_array};
 use glam::{DQuat, DVec3};
 use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelIterator;
 use std::f64::consts::PI;
 
+// Pixels of one ring of a crater and the depth added to each of them
+type CraterRing = (Vec<(CubeMapFace, usize, usize)>, f64);
+
 pub fn add_craters(
     cube_map_height: &mut CubeMapDataLayer<f64>,
     cube_map_biome: &CubeMapDataLayer<InterpolatedBiomeData>,
     terrain_radius: f64,
-    seed: f64,
+    seed: u64,
     count: u32,
+    deterministic: bool,
 ) {
     println!("Adding craters");
+    // without the deterministic mode the rings are stamped from many threads at once
+    let atomic_height = (!deterministic).then(|| cube_map_height.to_atomic());
     let mut added = 0;
-    let mut seed = seed;
+    let mut attempt = 0;
     loop {
-        let random_dir = (random_1d_to_3d(seed) * 2.0 - 1.0).normalize();
+        let [x, y, z, size_random, probability] = random_seed_to_array(derive_seed(seed, attempt));
+        attempt += 1;
+
+        let random_dir = (DVec3::new(x, y, z) * 2.0 - 1.0).normalize();
         let biome = cube_map_biome.get(random_dir);
 
         let size = map(
-            random_1d_to_1d(seed).powf(2.0), // make smaller craters more likely
+            size_random.powf(2.0), // make smaller craters more likely
             0.0,
             1.0,
             biome.min_crater_size as f64,
             biome.max_crater_size as f64,
         );
-        let probability = random_1d_to_1d(seed + 135.123);
-
-        seed += 567567.45546567;
 
         if probability < biome.craters_probability as f64 {
             continue;
@@ -45,29 +51,43 @@ pub fn add_craters(
             DVec3::new(1.0, 0.0, 0.0).cross(random_dir).normalize()
         };
 
-        (0..distance_steps).into_par_iter().for_each(|d_step| {
-            let d = (d_step as f64) * pixel_size;
-            let percentage = d_step as f64 / distance_steps as f64;
-
-            let depth = if percentage > 0.6 {
-                // looking from edge to center
-                1.0 - ((percentage - 0.6) / 0.4) // this needs to go from 0 to 1
-            } else {
-                ((percentage / 0.6 - 1.0) * 3.0) + 1.0 // this needs to go from 1 to like -3 or -4
-            };
-            let depth = depth * 0.00003 * size;
-
-            let dist = (d_step as f64 / distance_steps as f64) * (size / terrain_radius);
-
-            let ciricumfence = 2.0 * PI * dist;
-            let circle_steps = (ciricumfence / pixel_size) as i32 * 100 + 50;
-            for a_step in 0..circle_steps {
-                let rad = 2.0 * PI * (a_step as f64 / circle_steps as f64);
-                let orient = DQuat::from_axis_angle(random_dir, rad);
-                let vec = (random_dir + orient * surface_tangent * dist).normalize();
-                cube_map_height.add(vec, depth);
+        // rings are collected in order so the deterministic mode can apply them sequentially
+        let rings: Vec<CraterRing> = (0..distance_steps)
+            .into_par_iter()
+            .map(|d_step| {
+                let percentage = d_step as f64 / distance_steps as f64;
+
+                let depth = if percentage > 0.6 {
+                    // looking from edge to center
+                    1.0 - ((percentage - 0.6) / 0.4) // this needs to go from 0 to 1
+                } else {
+                    ((percentage / 0.6 - 1.0) * 3.0) + 1.0 // this needs to go from 1 to like -3 or -4
+                };
+                let depth = depth * 0.00003 * size;
+
+                let dist = (d_step as f64 / distance_steps as f64) * (size / terrain_radius);
+
+                let ciricumfence = 2.0 * PI * dist;
+                let circle_steps = (ciricumfence / pixel_size) as i32 * 100 + 50;
+                let mut pixels = vec![];
+                for a_step in 0..circle_steps {
+                    let rad = 2.0 * PI * (a_step as f64 / circle_steps as f64);
+                    let orient = DQuat::from_axis_angle(random_dir, rad);
+                    let vec = (random_dir + orient * surface_tangent * dist).normalize();
+                    match &atomic_height {
+                        Some(atomic_height) => atomic_height.add(vec, depth),
+                        None => pixels.push(cube_map_height.direction_to_pixel_coords(vec)),
+                    }
+                }
+                (pixels, depth)
+            })
+            .collect();
+
+        for (pixels, depth) in rings {
+            for (face, x, y) in pixels {
+                cube_map_height.add_pixel(&face, x, y, depth);
             }
-        });
+        }
 
         added += 1;
 
@@ -77,4 +97,8 @@ pub fn add_craters(
             break;
         }
     }
+
+    if let Some(atomic_height) = atomic_height {
+        cube_map_height.copy_from_atomic(&atomic_height);
+    }
 }
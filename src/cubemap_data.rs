@@ -1,20 +1,73 @@
+use crate::cubemap_storage::{FaceData, LayerStorage};
 use crate::math_util::mix;
-use glam::{DMat3, DMat4, DVec2, DVec3, DVec4, Mat4, Vec4Swizzles};
+use glam::{DMat3, DVec2, DVec3, Mat4};
+use rayon::prelude::*;
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::f64::consts::PI;
 use std::fmt;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+// Faces are plain slices in the CubeMapFace::index order, so sampling only needs &self and takes
+// no locks. Whole faces are written through &mut (faces_mut splits them up for parallel passes),
+// scattered writes from many threads go through a CubeMapDataLayer<AtomicF64> instead.
+// The slices are in tile order, see face_pixel_index
 pub struct CubeMapDataLayer<Data> {
-    pub res: u16,
-
-    px: Arc<Mutex<Vec<Data>>>,
-    py: Arc<Mutex<Vec<Data>>>,
-    pz: Arc<Mutex<Vec<Data>>>,
-    nx: Arc<Mutex<Vec<Data>>>,
-    ny: Arc<Mutex<Vec<Data>>>,
-    nz: Arc<Mutex<Vec<Data>>>,
+    pub res: u32,
+
+    storage: LayerStorage,
+    faces: [FaceData<Data>; 6],
+}
+
+// Faces are split into square tiles of TILE_SIZE pixels (or the whole face when it is smaller),
+// stored one after another in rows, with the pixels of every tile in rows too. Neighbouring
+// pixels then mostly share a few pages, which keeps the mapped layers from reading in whole rows
+// of a huge face for every lookup
+pub const TILE_SIZE: usize = 64;
+
+// An f64 many threads can add to at once
+pub struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    pub fn new(value: f64) -> AtomicF64 {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn fetch_add(&self, value: f64) -> f64 {
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            })
+            .unwrap();
+        f64::from_bits(previous)
+    }
+}
+
+// Layer elements that can be filtered as a single number
+pub trait LayerValue {
+    fn value(&self) -> f64;
+}
+
+impl LayerValue for f64 {
+    fn value(&self) -> f64 {
+        *self
+    }
+}
+
+impl LayerValue for f32 {
+    fn value(&self) -> f64 {
+        *self as f64
+    }
+}
+
+impl LayerValue for AtomicF64 {
+    fn value(&self) -> f64 {
+        self.load()
+    }
 }
 
 #[derive(Clone)]
@@ -27,6 +80,19 @@ pub enum CubeMapFace {
     NZ,
 }
 
+impl CubeMapFace {
+    pub fn index(&self) -> usize {
+        match self {
+            CubeMapFace::PX => 0,
+            CubeMapFace::PY => 1,
+            CubeMapFace::PZ => 2,
+            CubeMapFace::NX => 3,
+            CubeMapFace::NY => 4,
+            CubeMapFace::NZ => 5,
+        }
+    }
+}
+
 impl fmt::Display for CubeMapFace {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -40,166 +106,200 @@ impl fmt::Display for CubeMapFace {
     }
 }
 
-fn create_projection(face: &CubeMapFace) -> DMat4 {
-    let perspective = DMat4::perspective_rh_gl(PI / 2.0, 1.0, 0.01, 10.0);
-    let view = match (face) {
-        CubeMapFace::PX => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(1.0, 0.0, 0.0),
-            DVec3::new(0.0, 1.0, 0.0),
-        ),
-        CubeMapFace::PY => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(0.0, 1.0, 0.0),
-            DVec3::new(0.0, 0.0, -1.0),
-        ),
-        CubeMapFace::PZ => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(0.0, 0.0, 1.0),
-            DVec3::new(0.0, 1.0, 0.0),
-        ),
-        CubeMapFace::NX => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(-1.0, 0.0, 0.0),
-            DVec3::new(0.0, 1.0, 0.0),
-        ),
-        CubeMapFace::NY => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(0.0, -1.0, 0.0),
-            DVec3::new(0.0, 0.0, 1.0),
-        ),
-        CubeMapFace::NZ => DMat4::look_to_rh(
-            DVec3::new(0.0, 0.0, 0.0),
-            DVec3::new(0.0, 0.0, -1.0),
-            DVec3::new(0.0, 1.0, 0.0),
-        ),
-    };
-    perspective.mul_mat4(&view)
+// Forward, right and up of every face, in the CubeMapFace::index order. These are the axes of
+// a 90 degree right handed camera looking out of the face center, the face covers -1..1 along
+// right and up at distance 1
+struct FaceBasis {
+    forward: DVec3,
+    right: DVec3,
+    up: DVec3,
 }
 
+const FACE_BASES: [FaceBasis; 6] = [
+    // PX
+    FaceBasis {
+        forward: DVec3::new(1.0, 0.0, 0.0),
+        right: DVec3::new(0.0, 0.0, 1.0),
+        up: DVec3::new(0.0, 1.0, 0.0),
+    },
+    // PY
+    FaceBasis {
+        forward: DVec3::new(0.0, 1.0, 0.0),
+        right: DVec3::new(-1.0, 0.0, 0.0),
+        up: DVec3::new(0.0, 0.0, -1.0),
+    },
+    // PZ
+    FaceBasis {
+        forward: DVec3::new(0.0, 0.0, 1.0),
+        right: DVec3::new(-1.0, 0.0, 0.0),
+        up: DVec3::new(0.0, 1.0, 0.0),
+    },
+    // NX
+    FaceBasis {
+        forward: DVec3::new(-1.0, 0.0, 0.0),
+        right: DVec3::new(0.0, 0.0, -1.0),
+        up: DVec3::new(0.0, 1.0, 0.0),
+    },
+    // NY
+    FaceBasis {
+        forward: DVec3::new(0.0, -1.0, 0.0),
+        right: DVec3::new(-1.0, 0.0, 0.0),
+        up: DVec3::new(0.0, 0.0, 1.0),
+    },
+    // NZ
+    FaceBasis {
+        forward: DVec3::new(0.0, 0.0, -1.0),
+        right: DVec3::new(1.0, 0.0, 0.0),
+        up: DVec3::new(0.0, 1.0, 0.0),
+    },
+];
+
+const FACES: [CubeMapFace; 6] = [
+    CubeMapFace::PX,
+    CubeMapFace::PY,
+    CubeMapFace::PZ,
+    CubeMapFace::NX,
+    CubeMapFace::NY,
+    CubeMapFace::NZ,
+];
+
 fn project_direction(face: &CubeMapFace, coord: DVec3) -> Option<DVec2> {
-    let projection = create_projection(face);
-    let transformed = projection * DVec4::from((coord, 1.0));
-    let res = transformed.xyz() / transformed.w;
+    project_direction_with_tolerance(face, coord, 0.000000000000002)
+}
 
-    let tolerance = 0.000000000000002;
+// Scale of the uv returned from the projection, keeps the uv below 1 so it never floors to res
+const UV_SCALE: f64 = 0.99999999999;
+
+fn project_direction_with_tolerance(
+    face: &CubeMapFace,
+    coord: DVec3,
+    tolerance: f64,
+) -> Option<DVec2> {
+    let basis = &FACE_BASES[face.index()];
+    let forward = basis.forward.dot(coord);
+    if forward <= 0.0 {
+        return None;
+    }
+    let res = DVec2::new(basis.right.dot(coord), basis.up.dot(coord)) / forward;
 
-    if (res.x < -1.0 - tolerance
+    if res.x < -1.0 - tolerance
         || res.x > 1.0 + tolerance
         || res.y < -1.0 - tolerance
         || res.y > 1.0 + tolerance
-        || res.z > 1.0 + tolerance)
     {
         return None;
     }
-    let mut uv = DVec2::new(-res.x, -res.y) * 0.5 + 0.5;
-    // println!("{face}: RES.Z is {}", res.z);
-    // match face {
-    //     CubeMapFace::NX => {
-    //         uv.y = 1.0 - uv.y
-    //     }
-    //     CubeMapFace::NY => uv.y = 1.0,
-    //     CubeMapFace::NZ => uv.y = 1.0,
-    //     _ => {}
-    // }
-    Some(uv * 0.99999999999)
+    let uv = DVec2::new(-res.x, -res.y) * 0.5 + 0.5;
+    Some(uv * UV_SCALE)
 }
 
+// The face along the major axis, ties on the edges go to the first face in the PX, PY, PZ, NX,
+// NY, NZ order
 fn get_face(coord: DVec3) -> CubeMapFace {
-    let projected = project_direction(&CubeMapFace::PX, coord);
-    if projected.is_some() {
-        return CubeMapFace::PX;
-    }
-    let projected = project_direction(&CubeMapFace::PY, coord);
-    if projected.is_some() {
-        return CubeMapFace::PY;
+    let major = coord.abs().max_element();
+    for face in &FACES {
+        let forward = FACE_BASES[face.index()].forward.dot(coord);
+        if forward > 0.0 && forward >= major {
+            return face.clone();
+        }
     }
-    let projected = project_direction(&CubeMapFace::PZ, coord);
-    if projected.is_some() {
-        return CubeMapFace::PZ;
+    panic!("Impossible situation - no face found")
+}
+
+// Index of a pixel in the face slices, the tiles on the right and bottom edges are narrower when
+// the resolution is not a multiple of TILE_SIZE, so there is no padding
+pub fn face_pixel_index(res: u32, x: usize, y: usize) -> usize {
+    let res = res as usize;
+    if res <= TILE_SIZE {
+        return y * res + x;
     }
-    let projected = project_direction(&CubeMapFace::NX, coord);
-    if projected.is_some() {
-        return CubeMapFace::NX;
+    let (tile_x, tile_y) = (x / TILE_SIZE * TILE_SIZE, y / TILE_SIZE * TILE_SIZE);
+    let width = TILE_SIZE.min(res - tile_x);
+    let height = TILE_SIZE.min(res - tile_y);
+    tile_y * res + tile_x * height + (y - tile_y) * width + x - tile_x
+}
+
+// Same as CubeMapDataLayer::pixel_coords_to_direction, for when the layer is borrowed mutably
+pub fn face_pixel_to_direction(res: u32, face: &CubeMapFace, x: usize, y: usize) -> DVec3 {
+    face_uv_to_direction(
+        face,
+        DVec2::new(x as f64 / res as f64, y as f64 / res as f64),
+    )
+}
+
+// uv outside of 0..1 continues the face plane, so the direction ends up on a neighbouring face
+pub fn face_uv_to_direction(face: &CubeMapFace, uv: DVec2) -> DVec3 {
+    let basis = &FACE_BASES[face.index()];
+    let ndc = DVec2::new(-(uv.x * 2.0 - 1.0), -(uv.y * 2.0 - 1.0));
+    (basis.forward + basis.right * ndc.x + basis.up * ndc.y).normalize()
+}
+
+// Normal of the surface given by sample (the distance from the center for a direction), from
+// the samples dxrange away in 4 directions
+pub fn surface_normal(dir: DVec3, dxrange: f64, sample: impl Fn(DVec3) -> f64) -> DVec3 {
+    let dir = dir.normalize();
+
+    let tangdir = if dir.y.abs() < 0.99 {
+        DVec3::new(0.0, 1.0, 0.0).cross(dir).normalize()
+    } else {
+        DVec3::new(1.0, 0.0, 0.0).cross(dir).normalize()
+    };
+    let bitangdir = dir.cross(tangdir).normalize();
+
+    let dir1 = (dir + tangdir * dxrange).normalize();
+    let dir2 = (dir + bitangdir * dxrange).normalize();
+    let dir3 = (dir - tangdir * dxrange).normalize();
+    let dir4 = (dir - bitangdir * dxrange).normalize();
+
+    let p1 = dir1 * sample(dir1);
+    let p2 = dir2 * sample(dir2);
+    let p3 = dir3 * sample(dir3);
+    let p4 = dir4 * sample(dir4);
+
+    let n1 = (p2 - p1).cross(p3 - p1);
+    let n2 = (p3 - p1).cross(p4 - p1);
+
+    (n1 + n2).normalize()
+}
+
+impl<Data> CubeMapDataLayer<Data> {
+    fn is_out_of_bounds(&self, x: isize, y: isize) -> bool {
+        x < 0 || y < 0 || x >= self.res as isize || y >= self.res as isize
     }
-    let projected = project_direction(&CubeMapFace::NY, coord);
-    if projected.is_some() {
-        return CubeMapFace::NY;
+
+    // Index into the face data, coordinates past the edge are clamped to the last pixel
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        let max = self.res as usize - 1;
+        face_pixel_index(self.res, min(x, max), min(y, max))
     }
-    let projected = project_direction(&CubeMapFace::NZ, coord);
-    if projected.is_some() {
-        return CubeMapFace::NZ;
+
+    pub fn storage(&self) -> &LayerStorage {
+        &self.storage
     }
-    panic!("Impossible situation - no face found")
-}
 
-impl<Data: Clone> CubeMapDataLayer<Data> {
-    pub fn new(res: u16, initializer: Data) -> CubeMapDataLayer<Data> {
-        let res_usize = res as usize;
-        CubeMapDataLayer {
-            res: res,
-
-            px: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-            py: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-            pz: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-            nx: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-            ny: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-            nz: Arc::new(Mutex::from(vec![
-                initializer.clone();
-                res_usize * res_usize
-            ])),
-        }
+    pub fn face(&self, face: &CubeMapFace) -> &[Data] {
+        &self.faces[face.index()]
     }
 
-    fn is_out_of_bounds(&self, x: isize, y: isize) -> bool {
-        (x as u16) >= self.res || (y as u16) >= self.res || x < 0 || y < 0
-    }
-
-    pub fn set_pixel(&self, face: &CubeMapFace, x: usize, y: usize, value: Data) {
-        let index = y * (self.res as usize) + x;
-        match face {
-            CubeMapFace::PX => self.px.lock().unwrap()[index] = value,
-            CubeMapFace::PY => self.py.lock().unwrap()[index] = value,
-            CubeMapFace::PZ => self.pz.lock().unwrap()[index] = value,
-            CubeMapFace::NX => self.nx.lock().unwrap()[index] = value,
-            CubeMapFace::NY => self.ny.lock().unwrap()[index] = value,
-            CubeMapFace::NZ => self.nz.lock().unwrap()[index] = value,
-        }
+    pub fn face_mut(&mut self, face: &CubeMapFace) -> &mut [Data] {
+        &mut self.faces[face.index()]
     }
 
-    pub fn get_mutable_face(&self, face: &CubeMapFace) -> Arc<Mutex<Vec<Data>>> {
-        match face {
-            CubeMapFace::PX => self.px.clone(),
-            CubeMapFace::PY => self.py.clone(),
-            CubeMapFace::PZ => self.pz.clone(),
-            CubeMapFace::NX => self.nx.clone(),
-            CubeMapFace::NY => self.ny.clone(),
-            CubeMapFace::NZ => self.nz.clone(),
-        }
+    // All faces at once, to be handed out to different threads
+    pub fn faces_mut(&mut self) -> [(CubeMapFace, &mut [Data]); 6] {
+        let [px, py, pz, nx, ny, nz] = self.faces.each_mut();
+        [
+            (CubeMapFace::PX, &mut **px),
+            (CubeMapFace::PY, &mut **py),
+            (CubeMapFace::PZ, &mut **pz),
+            (CubeMapFace::NX, &mut **nx),
+            (CubeMapFace::NY, &mut **ny),
+            (CubeMapFace::NZ, &mut **nz),
+        ]
     }
 
     pub fn pixel_coords_to_direction(&self, face: &CubeMapFace, x: usize, y: usize) -> DVec3 {
-        let inv_projection = create_projection(face).inverse();
-        let uvx = x as f64 / self.res as f64;
-        let uvy = y as f64 / self.res as f64;
-        let clip = DVec4::new(-(uvx * 2.0 - 1.0), -(uvy * 2.0 - 1.0), 0.1, 1.0);
-        let transformed = inv_projection * clip;
-        (transformed.xyz() / transformed.w).normalize()
+        face_pixel_to_direction(self.res, face, x, y)
     }
 
     pub fn get_smallest_pixel_distance(&self) -> f64 {
@@ -232,93 +332,160 @@ impl<Data: Clone> CubeMapDataLayer<Data> {
         a.distance(b)
     }
 
-    // TODO if this is to be used, it needs to also do bilinear filtering
-    // maybe later
-    pub fn set(&self, coord: DVec3, value: Data) {
+    // Face and pixel containing the direction, clamped to the face
+    pub fn direction_to_pixel_coords(&self, coord: DVec3) -> (CubeMapFace, usize, usize) {
         let face = get_face(coord);
         let uv01 = project_direction(&face, coord).unwrap();
-        let uv = (uv01 * (self.res as f64)).floor();
-        let index = (uv.y * (self.res as f64) + uv.x) as usize;
-        match face {
-            CubeMapFace::PX => self.px.lock().unwrap()[index] = value,
-            CubeMapFace::PY => self.py.lock().unwrap()[index] = value,
-            CubeMapFace::PZ => self.pz.lock().unwrap()[index] = value,
-            CubeMapFace::NX => self.nx.lock().unwrap()[index] = value,
-            CubeMapFace::NY => self.ny.lock().unwrap()[index] = value,
-            CubeMapFace::NZ => self.nz.lock().unwrap()[index] = value,
-        }
+        let uv = uv01 * (self.res as f64);
+        let max = (self.res - 1) as f64;
+        let pixel = uv.floor().clamp(DVec2::ZERO, DVec2::splat(max));
+        (face, pixel.x as usize, pixel.y as usize)
     }
 
-    pub fn get_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> Data {
-        let index = min(
-            y * (self.res as usize) + x,
-            (self.res as usize) * (self.res as usize) - 1,
+    // Face and index of the pixel the direction floors to
+    fn direction_to_index(&self, coord: DVec3) -> (CubeMapFace, usize) {
+        let (face, x, y) = self.direction_to_pixel_coords(coord);
+        (face, self.pixel_index(x, y))
+    }
+}
+
+impl<Data: Send> CubeMapDataLayer<Data> {
+    // Sets every pixel to map(face, x, y, direction), the tiles of all faces run in parallel
+    pub fn par_map_pixels(
+        &mut self,
+        map: impl Fn(&CubeMapFace, usize, usize, DVec3) -> Data + Sync,
+    ) {
+        self.par_map_pixels_init(|| (), |_, face, x, y, dir| map(face, x, y, dir));
+    }
+
+    // Like par_map_pixels, with scratch space from init that is reused for the pixels of a thread
+    // instead of allocated per pixel, see rayon's map_init
+    pub fn par_map_pixels_init<Scratch>(
+        &mut self,
+        init: impl Fn() -> Scratch + Sync + Send,
+        map: impl Fn(&mut Scratch, &CubeMapFace, usize, usize, DVec3) -> Data + Sync,
+    ) {
+        let cube_map_res = self.res;
+        let res = cube_map_res as usize;
+        let tile = TILE_SIZE.min(res);
+        self.faces_mut()
+            .into_par_iter()
+            .for_each(|(face, face_data)| {
+                face_data.par_chunks_mut(tile * res).enumerate().for_each(
+                    |(tile_row, row_data)| {
+                        let tile_y = tile_row * tile;
+                        let height = tile.min(res - tile_y);
+                        row_data
+                            .par_chunks_mut(tile * height)
+                            .enumerate()
+                            .for_each_init(&init, |scratch, (tile_column, tile_data)| {
+                                let tile_x = tile_column * tile;
+                                let width = tile.min(res - tile_x);
+                                for (index, value) in tile_data.iter_mut().enumerate() {
+                                    let (x, y) = (tile_x + index % width, tile_y + index / width);
+                                    let dir = face_pixel_to_direction(cube_map_res, &face, x, y);
+                                    *value = map(scratch, &face, x, y, dir);
+                                }
+                            });
+                    },
+                );
+            });
+    }
+
+    // Like par_map_pixels, also handing over the pixel of other at the same place, both layers
+    // need the same resolution
+    pub fn par_zip_pixels<Other: Sync>(
+        &mut self,
+        other: &CubeMapDataLayer<Other>,
+        map: impl Fn(&CubeMapFace, usize, usize, DVec3, &Other) -> Data + Sync,
+    ) {
+        assert_eq!(
+            self.res, other.res,
+            "Zipped layers need the same resolution"
         );
-        match face {
-            CubeMapFace::PX => self.px.lock().unwrap()[index].clone(),
-            CubeMapFace::PY => self.py.lock().unwrap()[index].clone(),
-            CubeMapFace::PZ => self.pz.lock().unwrap()[index].clone(),
-            CubeMapFace::NX => self.nx.lock().unwrap()[index].clone(),
-            CubeMapFace::NY => self.ny.lock().unwrap()[index].clone(),
-            CubeMapFace::NZ => self.nz.lock().unwrap()[index].clone(),
+        self.par_map_pixels(|face, x, y, dir| {
+            map(face, x, y, dir, &other.face(face)[other.pixel_index(x, y)])
+        });
+    }
+}
+
+impl<Data: Clone> CubeMapDataLayer<Data> {
+    pub fn new(res: u32, initializer: Data) -> CubeMapDataLayer<Data> {
+        CubeMapDataLayer::new_in(res, initializer, &LayerStorage::Memory)
+    }
+
+    // Panics if the mapped files cannot be created
+    pub fn new_in(res: u32, initializer: Data, storage: &LayerStorage) -> CubeMapDataLayer<Data> {
+        let len = res as usize * res as usize;
+        CubeMapDataLayer {
+            res,
+            storage: storage.clone(),
+            faces: std::array::from_fn(|_| {
+                FaceData::new(storage, len, |_| initializer.clone())
+                    .expect("Failed to create the layer storage")
+            }),
         }
     }
 
+    pub fn set_pixel(&mut self, face: &CubeMapFace, x: usize, y: usize, value: Data) {
+        let index = self.pixel_index(x, y);
+        self.faces[face.index()][index] = value;
+    }
+
+    // TODO if this is to be used, it needs to also do bilinear filtering
+    // maybe later
+    pub fn set(&mut self, coord: DVec3, value: Data) {
+        let (face, index) = self.direction_to_index(coord);
+        self.faces[face.index()][index] = value;
+    }
+
+    pub fn get_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> Data {
+        self.faces[face.index()][self.pixel_index(x, y)].clone()
+    }
+
     pub fn get(&self, coord: DVec3) -> Data {
-        let face = get_face(coord);
-        let uv01 = project_direction(&face, coord).unwrap();
-        let uv = (uv01 * (self.res as f64));
-        let mut pixel = uv.floor();
-        if (pixel.x < 0.0) {
-            pixel.x = 0.0;
-        }
-        if (pixel.x >= (self.res - 1) as f64) {
-            pixel.x = (self.res - 1) as f64;
-        }
-        if (pixel.y < 0.0) {
-            pixel.y = 0.0;
-        }
-        if (pixel.y >= (self.res - 1) as f64) {
-            pixel.y = (self.res - 1) as f64;
-        }
-        self.get_pixel(&face, pixel.x as usize, pixel.y as usize)
+        let (face, x, y) = self.direction_to_pixel_coords(coord);
+        self.get_pixel(&face, x, y)
     }
 }
 
 impl CubeMapDataLayer<f64> {
     // TODO if this is to be used, it needs to also do bilinear filtering
     // maybe later
-    pub fn add(&self, coord: DVec3, value: f64) {
-        let face = get_face(coord);
-        let uv01 = project_direction(&face, coord).unwrap();
-        let uv = (uv01 * (self.res as f64)).floor();
-        let index = (uv.y * (self.res as f64) + uv.x) as usize;
-        match face {
-            CubeMapFace::PX => self.px.lock().unwrap()[index] += value,
-            CubeMapFace::PY => self.py.lock().unwrap()[index] += value,
-            CubeMapFace::PZ => self.pz.lock().unwrap()[index] += value,
-            CubeMapFace::NX => self.nx.lock().unwrap()[index] += value,
-            CubeMapFace::NY => self.ny.lock().unwrap()[index] += value,
-            CubeMapFace::NZ => self.nz.lock().unwrap()[index] += value,
+    pub fn add(&mut self, coord: DVec3, value: f64) {
+        let (face, index) = self.direction_to_index(coord);
+        self.faces[face.index()][index] += value;
+    }
+
+    pub fn add_pixel(&mut self, face: &CubeMapFace, x: usize, y: usize, value: f64) {
+        let index = self.pixel_index(x, y);
+        self.faces[face.index()][index] += value;
+    }
+
+    // Copy that many threads can add to at once, see copy_from_atomic for getting it back. Kept in
+    // the same storage as the layer
+    pub fn to_atomic(&self) -> CubeMapDataLayer<AtomicF64> {
+        CubeMapDataLayer {
+            res: self.res,
+            storage: self.storage.clone(),
+            faces: self.faces.each_ref().map(|face| {
+                FaceData::new(&self.storage, face.len(), |index| {
+                    AtomicF64::new(face[index])
+                })
+                .expect("Failed to create the layer storage")
+            }),
         }
     }
 
-    pub fn add_pixel(&self, face: &CubeMapFace, x: usize, y: usize, value: f64) {
-        let index = min(
-            y * (self.res as usize) + x,
-            (self.res as usize) * (self.res as usize) - 1,
-        );
-        match face {
-            CubeMapFace::PX => self.px.lock().unwrap()[index] += value,
-            CubeMapFace::PY => self.py.lock().unwrap()[index] += value,
-            CubeMapFace::PZ => self.pz.lock().unwrap()[index] += value,
-            CubeMapFace::NX => self.nx.lock().unwrap()[index] += value,
-            CubeMapFace::NY => self.ny.lock().unwrap()[index] += value,
-            CubeMapFace::NZ => self.nz.lock().unwrap()[index] += value,
+    pub fn copy_from_atomic(&mut self, atomic: &CubeMapDataLayer<AtomicF64>) {
+        for (face, atomic_face) in self.faces.iter_mut().zip(&atomic.faces) {
+            for (value, atomic_value) in face.iter_mut().zip(atomic_face.iter()) {
+                *value = atomic_value.load();
+            }
         }
     }
 
-    pub fn add_bilinear(&self, coord: DVec3, value: f64) {
+    pub fn add_bilinear(&mut self, coord: DVec3, value: f64) {
         let face = get_face(coord);
         let uv01 = project_direction(&face, coord).unwrap();
         let uv = (uv01 * (self.res as f64));
@@ -356,26 +523,61 @@ impl CubeMapDataLayer<f64> {
             value * (1.0 - pixel_fract.y),
         );
     }
+}
 
+impl CubeMapDataLayer<AtomicF64> {
+    pub fn add(&self, coord: DVec3, value: f64) {
+        let (face, index) = self.direction_to_index(coord);
+        self.faces[face.index()][index].fetch_add(value);
+    }
+
+    pub fn add_pixel(&self, face: &CubeMapFace, x: usize, y: usize, value: f64) {
+        self.faces[face.index()][self.pixel_index(x, y)].fetch_add(value);
+    }
+}
+
+impl<Data: LayerValue> CubeMapDataLayer<Data> {
+    // Texel shared by all the faces meeting at a grid point. The texel grids of two faces line up
+    // along their common edge, so a grid point on an edge or corner is the average of the closest
+    // texel of every face touching it, no matter which face it is looked up from. That includes
+    // the points at index res, past the last stored row and column
+    fn get_seam_texel(&self, face: &CubeMapFace, x: usize, y: usize) -> f64 {
+        let res = self.res as usize;
+        if x > 0 && x < res && y > 0 && y < res {
+            return self.faces[face.index()][self.pixel_index(x, y)].value();
+        }
+        let dir = self.pixel_coords_to_direction(face, x, y);
+        let mut sum = 0.0;
+        let mut count = 0;
+        for other in &FACES {
+            if let Some(uv) = project_direction_with_tolerance(other, dir, 0.000000001) {
+                let max = (res - 1) as f64;
+                let pixel = (uv / UV_SCALE * (res as f64))
+                    .round()
+                    .clamp(DVec2::ZERO, DVec2::splat(max));
+                let index = self.pixel_index(pixel.x as usize, pixel.y as usize);
+                sum += self.faces[other.index()][index].value();
+                count += 1;
+            }
+        }
+        sum / count as f64
+    }
+
+    // Continuous across the face edges and corners, see get_seam_texel
     pub fn get_bilinear(&self, coord: DVec3) -> f64 {
         let face = get_face(coord);
         let uv01 = project_direction(&face, coord).unwrap();
-        let uv = (uv01 * (self.res as f64));
-
-        let mut pixel1 = uv.floor();
-        let mut pixel2 = uv.ceil();
-        let pixel_fract = uv.fract_gl();
+        let uv = uv01 * (self.res as f64);
 
-        if (self.is_out_of_bounds(pixel1.x as isize, pixel1.y as isize)) {
-            pixel1.clone_from(&pixel2);
-        } else if (self.is_out_of_bounds(pixel2.x as isize, pixel2.y as isize)) {
-            pixel2.clone_from(&pixel1);
-        }
+        let pixel = uv.floor();
+        let pixel_fract = uv - pixel;
+        let (x1, y1) = (pixel.x as usize, pixel.y as usize);
+        let (x2, y2) = (x1 + 1, y1 + 1);
 
-        let value11 = self.get_pixel(&face, pixel1.x as usize, pixel1.y as usize);
-        let value12 = self.get_pixel(&face, pixel1.x as usize, pixel2.y as usize);
-        let value21 = self.get_pixel(&face, pixel2.x as usize, pixel1.y as usize);
-        let value22 = self.get_pixel(&face, pixel2.x as usize, pixel2.y as usize);
+        let value11 = self.get_seam_texel(&face, x1, y1);
+        let value12 = self.get_seam_texel(&face, x1, y2);
+        let value21 = self.get_seam_texel(&face, x2, y1);
+        let value22 = self.get_seam_texel(&face, x2, y2);
 
         let d1 = mix(value11, value21, pixel_fract.x);
         let d2 = mix(value12, value22, pixel_fract.x);
@@ -384,39 +586,20 @@ impl CubeMapDataLayer<f64> {
     }
 
     pub fn get_normal(&self, dir: DVec3, dxrange: f64) -> DVec3 {
-        let dir = dir.normalize();
-
-        let tangdir = if dir.y.abs() < 0.99 {
-            DVec3::new(0.0, 1.0, 0.0).cross(dir).normalize()
-        } else {
-            DVec3::new(1.0, 0.0, 0.0).cross(dir).normalize()
-        };
-        let bitangdir = dir.cross(tangdir).normalize();
-
-        let dir1 = (dir + tangdir * dxrange).normalize();
-        let dir2 = (dir + bitangdir * dxrange).normalize();
-        let dir3 = (dir - tangdir * dxrange).normalize();
-        let dir4 = (dir - bitangdir * dxrange).normalize();
-
-        let p1 = dir1 * self.get_bilinear(dir1);
-        let p2 = dir2 * self.get_bilinear(dir2);
-        let p3 = dir3 * self.get_bilinear(dir3);
-        let p4 = dir4 * self.get_bilinear(dir4);
-
-        let n1 = (p2 - p1).cross(p3 - p1);
-        let n2 = (p3 - p1).cross(p4 - p1);
-
-        (n1 + n2).normalize()
+        surface_normal(dir, dxrange, |dir| self.get_bilinear(dir))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use glam::{DMat4, DVec4, Vec4Swizzles};
+    use std::f64::consts::PI;
+    use std::sync::atomic::AtomicUsize;
 
     #[test]
     fn test_reprojection_px() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::PX, 32, 32);
@@ -429,7 +612,7 @@ mod tests {
 
     #[test]
     fn test_reprojection_py() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::PY, 32, 32);
@@ -442,7 +625,7 @@ mod tests {
 
     #[test]
     fn test_reprojection_pz() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::PZ, 32, 32);
@@ -455,7 +638,7 @@ mod tests {
 
     #[test]
     fn test_reprojection_nx() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::NX, 32, 32);
@@ -468,7 +651,7 @@ mod tests {
 
     #[test]
     fn test_reprojection_ny() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::NY, 32, 32);
@@ -481,7 +664,7 @@ mod tests {
 
     #[test]
     fn test_reprojection_nz() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let dir = cube_map.pixel_coords_to_direction(&CubeMapFace::NZ, 32, 32);
@@ -492,9 +675,184 @@ mod tests {
         assert_eq!(pixels.y, 31.0);
     }
 
+    // The camera matrices the faces were originally projected with
+    fn matrix_projection(face: &CubeMapFace) -> DMat4 {
+        let (forward, up) = match face {
+            CubeMapFace::PX => (DVec3::X, DVec3::Y),
+            CubeMapFace::PY => (DVec3::Y, DVec3::NEG_Z),
+            CubeMapFace::PZ => (DVec3::Z, DVec3::Y),
+            CubeMapFace::NX => (DVec3::NEG_X, DVec3::Y),
+            CubeMapFace::NY => (DVec3::NEG_Y, DVec3::Z),
+            CubeMapFace::NZ => (DVec3::NEG_Z, DVec3::Y),
+        };
+        DMat4::perspective_rh_gl(PI / 2.0, 1.0, 0.01, 10.0)
+            * DMat4::look_to_rh(DVec3::ZERO, forward, up)
+    }
+
+    #[test]
+    fn test_projection_matches_matrices() {
+        const RES: u32 = 64;
+        let cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        for face in &FACES {
+            let projection = matrix_projection(face);
+            let inv_projection = projection.inverse();
+            for y in 0..=RES as usize {
+                for x in 0..=RES as usize {
+                    let uvx = x as f64 / RES as f64;
+                    let uvy = y as f64 / RES as f64;
+                    let clip = DVec4::new(-(uvx * 2.0 - 1.0), -(uvy * 2.0 - 1.0), 0.1, 1.0);
+                    let transformed = inv_projection * clip;
+                    let expected = (transformed.xyz() / transformed.w).normalize();
+                    let dir = cube_map.pixel_coords_to_direction(face, x, y);
+                    assert!(dir.distance(expected) < 1e-12, "{face} {x} {y}");
+
+                    if x == RES as usize || y == RES as usize {
+                        continue;
+                    }
+                    // the texel center, so it is never on an edge shared with another face
+                    let inside =
+                        (dir + cube_map.pixel_coords_to_direction(face, x + 1, y + 1)).normalize();
+                    let transformed = projection * DVec4::from((inside, 1.0));
+                    let ndc = transformed.xyz() / transformed.w;
+                    let expected = (DVec2::new(-ndc.x, -ndc.y) * 0.5 + 0.5) * UV_SCALE;
+                    assert_eq!(get_face(inside).index(), face.index());
+                    let uv = project_direction(face, inside).unwrap();
+                    assert!(uv.distance(expected) < 1e-12, "{face} {x} {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bilinear_continuous_across_edges() {
+        const RES: u32 = 64;
+        let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        let faces = [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ];
+        for face in &faces {
+            for y in 0..RES as usize {
+                for x in 0..RES as usize {
+                    let dir = cube_map.pixel_coords_to_direction(face, x, y);
+                    cube_map.set_pixel(face, x, y, 3.0 * dir.x + 2.0 * dir.y - dir.z);
+                }
+            }
+        }
+
+        let normals = [
+            DVec3::X,
+            DVec3::Y,
+            DVec3::Z,
+            DVec3::NEG_X,
+            DVec3::NEG_Y,
+            DVec3::NEG_Z,
+        ];
+        let mut edges = 0;
+        for (i, a) in normals.iter().enumerate() {
+            for b in &normals[i + 1..] {
+                if a.dot(*b) != 0.0 {
+                    continue;
+                }
+                edges += 1;
+                // along the whole edge, corners included, stepping over it from one face to the other
+                let along = a.cross(*b);
+                let across = *a - *b;
+                for step in -50..=50 {
+                    let on_edge = *a + *b + along * (step as f64 / 50.0);
+                    let first = cube_map.get_bilinear((on_edge + across * 1e-9).normalize());
+                    let second = cube_map.get_bilinear((on_edge - across * 1e-9).normalize());
+                    assert!(
+                        (first - second).abs() < 1e-6,
+                        "edge {} {} at {}: {} vs {}",
+                        a,
+                        b,
+                        step,
+                        first,
+                        second
+                    );
+                }
+            }
+        }
+        assert_eq!(edges, 12);
+    }
+
+    #[test]
+    fn test_atomic_add() {
+        use rayon::prelude::*;
+
+        let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(16, 1.0);
+        let atomic = cube_map.to_atomic();
+        (0..1000).into_par_iter().for_each(|i| {
+            atomic.add(DVec3::X, 0.5);
+            atomic.add_pixel(&CubeMapFace::NZ, i % 16, 3, 1.0);
+        });
+        cube_map.copy_from_atomic(&atomic);
+
+        assert_eq!(cube_map.get(DVec3::X), 501.0);
+        assert_eq!(cube_map.get_pixel(&CubeMapFace::NZ, 0, 3), 1.0 + 63.0);
+        assert_eq!(cube_map.get_pixel(&CubeMapFace::NZ, 15, 3), 1.0 + 62.0);
+        assert_eq!(cube_map.get_pixel(&CubeMapFace::NZ, 0, 4), 1.0);
+    }
+
+    #[test]
+    fn test_tiled_layout() {
+        // a whole number of tiles, a smaller face than a tile, and narrower tiles on the edges
+        for res in [128_u32, 16, 100] {
+            let mut seen = vec![false; (res * res) as usize];
+            for y in 0..res as usize {
+                for x in 0..res as usize {
+                    let index = face_pixel_index(res, x, y);
+                    assert!(!seen[index], "{res}: {x} {y}");
+                    seen[index] = true;
+                }
+            }
+            // pixels of one tile stay together
+            if res > TILE_SIZE as u32 {
+                assert_eq!(face_pixel_index(res, 1, 0), 1);
+                assert_eq!(face_pixel_index(res, 0, 1), TILE_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn test_par_map_and_zip_pixels() {
+        const RES: u32 = 32;
+        let mut ids: CubeMapDataLayer<usize> = CubeMapDataLayer::new(RES, 0);
+        ids.par_map_pixels(|face, x, y, dir| {
+            assert_eq!(dir, face_pixel_to_direction(RES, face, x, y));
+            (face.index() * 100 + y) * 100 + x
+        });
+        assert_eq!(ids.get_pixel(&CubeMapFace::NX, 7, 31), (300 + 31) * 100 + 7);
+
+        let mut doubled: CubeMapDataLayer<usize> = CubeMapDataLayer::new(RES, 0);
+        doubled.par_zip_pixels(&ids, |_, _, _, _, id| id * 2);
+        for face in &FACES {
+            for (id, double) in ids.face(face).iter().zip(doubled.face(face)) {
+                assert_eq!(id * 2, *double);
+            }
+        }
+
+        // scratch is made at most once per tile, not per pixel
+        let inits = AtomicUsize::new(0);
+        let mut counted: CubeMapDataLayer<usize> = CubeMapDataLayer::new(RES, 0);
+        counted.par_map_pixels_init(
+            || inits.fetch_add(1, Ordering::Relaxed),
+            |_, face, x, y, _| ids.get_pixel(face, x, y),
+        );
+        assert!(inits.load(Ordering::Relaxed) <= FACES.len());
+        for face in &FACES {
+            assert_eq!(counted.face(face), ids.face(face));
+        }
+    }
+
     #[test]
     fn test_set_pixel() {
-        const RES: u16 = 128;
+        const RES: u32 = 128;
         let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
 
         let faces = [
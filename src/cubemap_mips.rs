@@ -0,0 +1,186 @@
+use crate::cubemap_data::{face_uv_to_direction, surface_normal, CubeMapDataLayer};
+use crate::math_util::mix;
+use glam::{DVec2, DVec3};
+use std::f64::consts::SQRT_2;
+
+// Halved resolutions of a layer down to 1 pixel per face. Every pixel is a 1-2-1 tent over the
+// 3x3 pixels of the finer level around it, read through get_bilinear so the pixels along the
+// face edges filter over the neighbouring faces too. Borrows the layer so it cannot change
+// while the chain is in use
+pub struct CubeMapMips<'a> {
+    base: &'a CubeMapDataLayer<f64>,
+    levels: MipLevels<'a>,
+}
+
+// The levels below the base, owned by the chain or kept by whoever owns the layer
+enum MipLevels<'a> {
+    Owned(Vec<CubeMapDataLayer<f64>>),
+    Borrowed(&'a [CubeMapDataLayer<f64>]),
+}
+
+impl std::ops::Deref for MipLevels<'_> {
+    type Target = [CubeMapDataLayer<f64>];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            MipLevels::Owned(levels) => levels,
+            MipLevels::Borrowed(levels) => levels,
+        }
+    }
+}
+
+const TENT: [(f64, f64); 3] = [(-1.0, 1.0), (0.0, 2.0), (1.0, 1.0)];
+
+fn downsample(fine: &CubeMapDataLayer<f64>) -> CubeMapDataLayer<f64> {
+    let res = (fine.res / 2).max(1);
+    let mut coarse = CubeMapDataLayer::new_in(res, 0.0, fine.storage());
+    let fine_texel = 1.0 / fine.res as f64;
+    coarse.par_map_pixels(|face, x, y, _| {
+        let center = DVec2::new(x as f64, y as f64) / res as f64;
+        let mut sum = 0.0;
+        for (offset_y, weight_y) in TENT {
+            for (offset_x, weight_x) in TENT {
+                let uv = center + DVec2::new(offset_x, offset_y) * fine_texel;
+                sum += fine.get_bilinear(face_uv_to_direction(face, uv)) * weight_x * weight_y;
+            }
+        }
+        sum / 16.0
+    });
+    coarse
+}
+
+impl CubeMapDataLayer<f64> {
+    pub fn build_mips(&self) -> CubeMapMips<'_> {
+        CubeMapMips {
+            base: self,
+            levels: MipLevels::Owned(self.build_mip_levels()),
+        }
+    }
+
+    // The levels of build_mips without the layer, for keeping them next to it
+    pub fn build_mip_levels(&self) -> Vec<CubeMapDataLayer<f64>> {
+        let mut levels: Vec<CubeMapDataLayer<f64>> = vec![];
+        while levels.last().map_or(self.res, |level| level.res) > 1 {
+            let next = downsample(levels.last().unwrap_or(self));
+            levels.push(next);
+        }
+        levels
+    }
+}
+
+impl<'a> CubeMapMips<'a> {
+    // levels has to come from build_mip_levels of base
+    pub fn from_levels(
+        base: &'a CubeMapDataLayer<f64>,
+        levels: &'a [CubeMapDataLayer<f64>],
+    ) -> CubeMapMips<'a> {
+        CubeMapMips {
+            base,
+            levels: MipLevels::Borrowed(levels),
+        }
+    }
+
+    // Level 0 is the layer itself
+    pub fn level(&self, level: usize) -> &CubeMapDataLayer<f64> {
+        match level {
+            0 => self.base,
+            _ => &self.levels[level - 1],
+        }
+    }
+
+    pub fn levels_count(&self) -> usize {
+        self.levels.len() + 1
+    }
+
+    // Bilinear from the two levels around footprint, the width of the sampled area on the unit
+    // sphere. Footprints up to a pixel give the plain get_bilinear of the layer
+    pub fn get_trilinear(&self, dir: DVec3, footprint: f64) -> f64 {
+        let pixel = self.base.get_pixel_distance_for_dir(dir) / SQRT_2;
+        let lod = (footprint / pixel)
+            .log2()
+            .clamp(0.0, (self.levels_count() - 1) as f64);
+        let lower = lod.floor() as usize;
+        let value = self.level(lower).get_bilinear(dir);
+        if lower + 1 == self.levels_count() {
+            return value;
+        }
+        mix(
+            value,
+            self.level(lower + 1).get_bilinear(dir),
+            lod - lower as f64,
+        )
+    }
+
+    // Like CubeMapDataLayer::get_normal, with the samples filtered to dxrange
+    pub fn get_normal(&self, dir: DVec3, dxrange: f64) -> DVec3 {
+        surface_normal(dir, dxrange, |dir| self.get_trilinear(dir, dxrange))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cubemap_data::CubeMapFace;
+
+    fn filled(
+        res: u32,
+        value: impl Fn(&CubeMapFace, usize, usize, DVec3) -> f64 + Sync,
+    ) -> CubeMapDataLayer<f64> {
+        let mut layer = CubeMapDataLayer::new(res, 0.0);
+        layer.par_map_pixels(value);
+        layer
+    }
+
+    #[test]
+    fn test_mip_chain() {
+        let constant = filled(64, |_, _, _, _| 5.0);
+        let mips = constant.build_mips();
+        let resolutions: Vec<u32> = (0..mips.levels_count())
+            .map(|level| mips.level(level).res)
+            .collect();
+        assert_eq!(resolutions, [64, 32, 16, 8, 4, 2, 1]);
+        for level in 1..mips.levels_count() {
+            let layer = mips.level(level);
+            for face in [CubeMapFace::PX, CubeMapFace::NY] {
+                assert!(layer
+                    .face(&face)
+                    .iter()
+                    .all(|value| (value - 5.0).abs() < 1e-12));
+            }
+        }
+
+        // a smooth layer keeps its shape, also right on the edges and corners, where the coarse
+        // levels bend the most
+        let smooth = filled(64, |_, _, _, dir| 3.0 * dir.x + 2.0 * dir.y - dir.z);
+        let mips = smooth.build_mips();
+        for i in 0..500 {
+            let dir = DVec3::new(
+                (i as f64 * 0.7).sin(),
+                (i as f64 * 1.3).cos(),
+                (i as f64 * 0.3).sin(),
+            )
+            .normalize();
+            let expected = 3.0 * dir.x + 2.0 * dir.y - dir.z;
+            assert!((mips.get_trilinear(dir, 0.0) - smooth.get_bilinear(dir)).abs() < 1e-12);
+            assert!((mips.get_trilinear(dir, 0.1) - expected).abs() < 0.3);
+        }
+    }
+
+    #[test]
+    fn test_trilinear_filters_out_aliasing() {
+        // a checkerboard of single pixels averages out to gray once the footprint covers a few
+        let checkerboard = filled(128, |_, x, y, _| ((x + y) % 2) as f64);
+        let mips = checkerboard.build_mips();
+        let footprint = checkerboard.get_biggest_pixel_distance() * 4.0;
+        for i in 0..500 {
+            let dir = DVec3::new(
+                (i as f64 * 0.7).sin(),
+                (i as f64 * 1.3).cos(),
+                (i as f64 * 0.3).sin(),
+            )
+            .normalize();
+            let filtered = mips.get_trilinear(dir, footprint);
+            assert!((filtered - 0.5).abs() < 0.1, "{dir}: {filtered}");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use memmap2::MmapMut;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::{needs_drop, size_of};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::slice;
+
+// Where the faces of a layer are kept. Mapped faces live in unnamed temporary files in the
+// directory, which the OS pages in and out of memory as the tiles are used and deletes once the
+// layer is dropped, so layers much bigger than the memory fit as long as the disk has the space
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum LayerStorage {
+    #[default]
+    Memory,
+    Mapped(PathBuf),
+}
+
+impl LayerStorage {
+    // generatorConfig.storageDir, memory if it is not set
+    pub fn from_dir(dir: Option<&str>) -> LayerStorage {
+        dir.map_or(LayerStorage::Memory, |dir| LayerStorage::Mapped(dir.into()))
+    }
+}
+
+// The pixels of one face, either way they are a plain slice to the rest of the code
+pub enum FaceData<Data> {
+    Memory(Vec<Data>),
+    Mapped {
+        map: MmapMut,
+        len: usize,
+        data: PhantomData<Data>,
+    },
+}
+
+impl<Data> FaceData<Data> {
+    // Mapped data is never dropped element by element, so only types without drop glue can be
+    // mapped, which covers the numbers and the biome data
+    pub fn new(
+        storage: &LayerStorage,
+        len: usize,
+        init: impl Fn(usize) -> Data,
+    ) -> io::Result<FaceData<Data>> {
+        let dir = match storage {
+            LayerStorage::Mapped(dir) if len * size_of::<Data>() > 0 => dir,
+            _ => return Ok(FaceData::Memory((0..len).map(init).collect())),
+        };
+        assert!(
+            !needs_drop::<Data>(),
+            "Only data without drop glue can be mapped"
+        );
+        let file = tempfile::tempfile_in(dir)?;
+        file.set_len((len * size_of::<Data>()) as u64)?;
+        // the file is unnamed and only ever mapped here, nothing else can change it under us
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let ptr = map.as_mut_ptr() as *mut Data;
+        for index in 0..len {
+            // maps are page aligned, enough for any of the pixel types
+            unsafe { ptr.add(index).write(init(index)) };
+        }
+        Ok(FaceData::Mapped {
+            map,
+            len,
+            data: PhantomData,
+        })
+    }
+}
+
+impl<Data> Deref for FaceData<Data> {
+    type Target = [Data];
+
+    fn deref(&self) -> &[Data] {
+        match self {
+            FaceData::Memory(data) => data,
+            // every element was written in new
+            FaceData::Mapped { map, len, .. } => unsafe {
+                slice::from_raw_parts(map.as_ptr() as *const Data, *len)
+            },
+        }
+    }
+}
+
+impl<Data> DerefMut for FaceData<Data> {
+    fn deref_mut(&mut self) -> &mut [Data] {
+        match self {
+            FaceData::Memory(data) => data,
+            FaceData::Mapped { map, len, .. } => unsafe {
+                slice::from_raw_parts_mut(map.as_mut_ptr() as *mut Data, *len)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
+    use glam::DVec3;
+    use std::env;
+
+    #[test]
+    fn test_mapped_layer_matches_memory() {
+        const RES: u32 = 100;
+        let storage = LayerStorage::Mapped(env::temp_dir());
+        let mut memory: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        let mut mapped: CubeMapDataLayer<f64> = CubeMapDataLayer::new_in(RES, 0.0, &storage);
+        assert_eq!(mapped.storage(), &storage);
+        memory.par_map_pixels(|_, _, _, dir| dir.x * 3.0 + dir.y * dir.z);
+        mapped.par_map_pixels(|_, _, _, dir| dir.x * 3.0 + dir.y * dir.z);
+
+        let atomic = mapped.to_atomic();
+        atomic.add_pixel(&CubeMapFace::PY, 99, 42, 1.0);
+        mapped.copy_from_atomic(&atomic);
+        memory.add_pixel(&CubeMapFace::PY, 99, 42, 1.0);
+
+        // f32 layers map and sample the same way, for data that does not need the precision
+        let mut compact: CubeMapDataLayer<f32> = CubeMapDataLayer::new_in(RES, 0.0, &storage);
+        compact.par_zip_pixels(&memory, |_, _, _, _, value| *value as f32);
+
+        for i in 0..200 {
+            let dir = DVec3::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos(), 0.3).normalize();
+            assert_eq!(mapped.get_bilinear(dir), memory.get_bilinear(dir));
+            assert!((compact.get_bilinear(dir) - memory.get_bilinear(dir)).abs() < 1e-6);
+        }
+        assert_eq!(
+            mapped.get_pixel(&CubeMapFace::PY, 99, 42),
+            memory.get_pixel(&CubeMapFace::PY, 99, 42)
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::json_input::{InputDomainWarp, InputNoiseType};
+use crate::noise::{fbm_with, noise_function, NoiseFunction};
+use crate::random::derive_seed;
+use glam::DVec3;
+
+struct Warp {
+    noise: Box<dyn NoiseFunction>,
+    // one seed per axis, so the offset is not along the diagonal
+    seeds: [u64; 3],
+    amplitude: f64,
+    frequency: f64,
+    octaves: u8,
+}
+
+// A chain of warps, empty leaves the positions as they are
+pub struct DomainWarp {
+    warps: Vec<Warp>,
+}
+
+impl DomainWarp {
+    pub fn new(warps: &[InputDomainWarp], default_noise: InputNoiseType, seed: u64) -> DomainWarp {
+        DomainWarp {
+            warps: warps
+                .iter()
+                .enumerate()
+                .map(|(index, warp)| {
+                    let warp_seed = derive_seed(seed, index as u64);
+                    Warp {
+                        noise: noise_function(warp.noise.unwrap_or(default_noise)),
+                        seeds: [0, 1, 2].map(|axis| derive_seed(warp_seed, axis)),
+                        amplitude: warp.amplitude,
+                        frequency: warp.frequency,
+                        octaves: warp.octaves,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    // Each warp samples the position the previous one moved, which folds the features over
+    pub fn warp(&self, pos: DVec3) -> DVec3 {
+        let mut pos = pos;
+        for warp in &self.warps {
+            if warp.octaves == 0 {
+                continue;
+            }
+            let sample_pos = pos * warp.frequency;
+            let [x, y, z] = warp.seeds.map(|seed| {
+                fbm_with(
+                    warp.noise.as_ref(),
+                    sample_pos,
+                    seed,
+                    warp.octaves,
+                    2.0,
+                    0.5,
+                ) * 2.0
+                    - 1.0
+            });
+            pos += DVec3::new(x, y, z) * warp.amplitude;
+        }
+        pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warp_offsets() {
+        let pos = DVec3::new(0.3, -0.5, 0.8).normalize();
+        assert_eq!(
+            DomainWarp::new(&[], InputNoiseType::Value, 1).warp(pos),
+            pos
+        );
+
+        let warps: Vec<InputDomainWarp> = serde_json::from_str(
+            r#"[
+                { "amplitude": 0.2, "frequency": 3 },
+                { "amplitude": 0.05, "frequency": 12, "octaves": 2, "noise": "simplex" }
+            ]"#,
+        )
+        .unwrap();
+        let warp = DomainWarp::new(&warps, InputNoiseType::Value, 1);
+        let mut moved = 0;
+        for i in 0..256 {
+            let pos = DVec3::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos(), 0.5).normalize();
+            let warped = warp.warp(pos);
+            assert_eq!(warped, warp.warp(pos));
+            // every axis of every warp moves by at most its amplitude
+            let offset = warped - pos;
+            assert!(offset.abs().max_element() <= 0.25 + 1e-12);
+            if offset.length() > 0.01 {
+                moved += 1;
+            }
+        }
+        assert!(moved > 128);
+        assert_ne!(
+            DomainWarp::new(&warps, InputNoiseType::Value, 2).warp(pos),
+            warp.warp(pos)
+        );
+    }
+}
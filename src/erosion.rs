@@ -1,19 +1,18 @@
 /*
-EROSION IDEA to make it parallel
+Erosion runs in two modes
 
-Create an ErosionDropletRun struct
-it will hold a vector list of struct of shape ErosionDropletModification {
-    vec3 dir (maybe pixels im not sure) pixels will be easier to apply
-    float delta
-}
+The default one runs the iterations in parallel and every droplet adds straight into an atomic
+copy of the height map, so droplets see each other's changes as they happen and the result
+depends on the thread scheduling.
 
-then in paraller per iteration i can spawn a droplet on random X pixels - maybe on all of them?
-iterate all teh droplers in parallel until all end their journeys
-resulting changes are then applied on the main data and the iteration restarts
+The deterministic one runs the iterations one after another. All droplets of an iteration run in
+parallel against the same heights and only record ErosionDropletModification deltas (pixels, as
+those are easier to apply), then the deltas are applied in a fixed order and the next iteration
+starts.
  */
-use crate::cubemap_data::CubeMapDataLayer;
+use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace, LayerValue};
 use crate::generate_terrain::InterpolatedBiomeData;
-use crate::random::{random_2d_to_3d, random_3d_to_3d};
+use crate::random::{random_2d_to_3d_seeded, random_3d_to_3d_seeded};
 use glam::{DVec2, DVec3};
 use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelIterator;
@@ -27,7 +26,9 @@ struct ErosionDroplet {
 }
 
 struct ErosionDropletModification {
-    position: DVec3,
+    face: CubeMapFace,
+    x: usize,
+    y: usize,
     delta: f64,
 }
 
@@ -35,7 +36,10 @@ fn asymptotic(x: f64, limit: f64) -> f64 {
     (1.0 - 1.0 / x.exp()) * limit
 }
 
-fn get_surface_normal(cubemap_data: &CubeMapDataLayer<f64>, smooth_normal: DVec3) -> DVec3 {
+fn get_surface_normal<Height: LayerValue>(
+    cubemap_data: &CubeMapDataLayer<Height>,
+    smooth_normal: DVec3,
+) -> DVec3 {
     cubemap_data.get_normal(
         smooth_normal,
         cubemap_data.get_pixel_distance_for_dir(smooth_normal),
@@ -84,79 +88,157 @@ fn get_droplet_deposit(droplet: &mut ErosionDroplet, slope: f64) -> f64 {
     deposit
 }
 
+struct ErosionSettings {
+    sphere_radius: f64,
+    seed: u64,
+    velocity_coefficient: f64,
+    evaporation_coefficient: f64,
+}
+
+// Simulates a single droplet until it evaporates or stops, height changes are handed to modify
+fn run_droplet<Height: LayerValue>(
+    cube_map_height: &CubeMapDataLayer<Height>,
+    cube_map_biome: &CubeMapDataLayer<InterpolatedBiomeData>,
+    settings: &ErosionSettings,
+    iteration: u16,
+    droplet_num: u16,
+    modify: &mut dyn FnMut(DVec3, f64),
+) {
+    let ErosionSettings {
+        sphere_radius,
+        seed,
+        velocity_coefficient,
+        evaporation_coefficient,
+    } = *settings;
+    let mut droplet = ErosionDroplet {
+        position: sphere_radius
+            * (random_2d_to_3d_seeded(DVec2::new(iteration as f64, droplet_num as f64), seed)
+                * 2.0
+                - 1.0)
+                .normalize(),
+        velocity: DVec3::new(0.0, 0.0, 0.0),
+        accumulation: 0.0,
+        water_left: 1.0,
+    };
+
+    while droplet.water_left > 0.0 {
+        let smooth_normal = droplet.position.normalize();
+        let surface_normal = get_surface_normal(cube_map_height, smooth_normal);
+
+        let slope = 1.0 - surface_normal.dot(smooth_normal).max(0.0).powf(88.0);
+
+        update_droplet_velocity(
+            &mut droplet,
+            smooth_normal,
+            surface_normal,
+            velocity_coefficient,
+        );
+
+        let biome = cube_map_biome.get(smooth_normal);
+
+        let mut delta = 0.0;
+
+        delta -= get_droplet_erosion(&mut droplet, slope) * biome.erosion_strength as f64;
+        delta += get_droplet_deposit(&mut droplet, slope) * biome.deposition_strength as f64;
+
+        for x in 0..16 {
+            modify(
+                (smooth_normal
+                    + 2.0
+                        * cube_map_height.get_pixel_distance_for_dir(smooth_normal)
+                        * random_3d_to_3d_seeded(
+                            DVec3::new(x as f64, iteration as f64, droplet_num as f64),
+                            seed,
+                        ))
+                .normalize(),
+                delta / 16.0,
+            );
+        }
+
+        update_droplet_position(&mut droplet, sphere_radius);
+
+        evaporate_droplet(&mut droplet, evaporation_coefficient);
+
+        if droplet.velocity.length() < 0.01 {
+            modify(smooth_normal, droplet.accumulation);
+            break;
+        }
+    }
+}
+
 pub fn erosion_run(
     cube_map_height: &mut CubeMapDataLayer<f64>,
     cube_map_biome: &mut CubeMapDataLayer<InterpolatedBiomeData>,
     iterations: u16,
     droplets_per_iteration: u16,
     sphere_radius: f64,
+    seed: u64,
     erosion_droplet_velocity_coefficient: f64,
     erosion_droplet_evaporation_coefficient: f64,
+    deterministic: bool,
 ) {
     println!(
         "Erosion started, {iterations} iterations, {droplets_per_iteration} droplets per iteration"
     );
-    let finished_iters = Arc::new(Mutex::from(0_i32));
-    (0..iterations).into_par_iter().for_each(|iteration| {
-        for droplet_num in (0..droplets_per_iteration) {
-            let mut droplet = ErosionDroplet {
-                position: sphere_radius
-                    * (random_2d_to_3d(DVec2::new(iteration as f64, droplet_num as f64)) * 2.0
-                        - 1.0)
-                        .normalize(),
-                velocity: DVec3::new(0.0, 0.0, 0.0),
-                accumulation: 0.0,
-                water_left: 1.0,
-            };
-
-            while droplet.water_left > 0.0 {
-                let smooth_normal = droplet.position.clone().normalize();
-                let surface_normal = get_surface_normal(&cube_map_height, smooth_normal);
-
-                let slope = 1.0 - surface_normal.dot(smooth_normal).max(0.0).powf(88.0);
-
-                update_droplet_velocity(
-                    &mut droplet,
-                    smooth_normal,
-                    surface_normal,
-                    erosion_droplet_velocity_coefficient,
-                );
 
-                let biome = cube_map_biome.get(smooth_normal);
-
-                let mut delta = 0.0;
-
-                delta -= get_droplet_erosion(&mut droplet, slope) * biome.erosion_strength as f64;
-                delta +=
-                    get_droplet_deposit(&mut droplet, slope) * biome.deposition_strength as f64;
-
-                for x in 0..16 {
-                    cube_map_height.add(
-                        (smooth_normal
-                            + 2.0
-                                * cube_map_height.get_pixel_distance_for_dir(smooth_normal)
-                                * random_3d_to_3d(DVec3::new(
-                                    x as f64,
-                                    iteration as f64,
-                                    droplet_num as f64,
-                                )))
-                        .normalize(),
-                        delta / 16.0,
+    let settings = ErosionSettings {
+        sphere_radius,
+        seed,
+        velocity_coefficient: erosion_droplet_velocity_coefficient,
+        evaporation_coefficient: erosion_droplet_evaporation_coefficient,
+    };
+
+    if deterministic {
+        for iteration in 0..iterations {
+            let modifications: Vec<Vec<ErosionDropletModification>> = (0..droplets_per_iteration)
+                .into_par_iter()
+                .map(|droplet_num| {
+                    let mut modifications = vec![];
+                    run_droplet(
+                        cube_map_height,
+                        cube_map_biome,
+                        &settings,
+                        iteration,
+                        droplet_num,
+                        &mut |position, delta| {
+                            let (face, x, y) = cube_map_height.direction_to_pixel_coords(position);
+                            modifications.push(ErosionDropletModification { face, x, y, delta });
+                        },
                     );
-                }
-
-                update_droplet_position(&mut droplet, sphere_radius);
-
-                evaporate_droplet(&mut droplet, erosion_droplet_evaporation_coefficient);
+                    modifications
+                })
+                .collect();
+
+            // always applied in the droplet order, so the sums do not depend on the scheduling
+            modifications.iter().flatten().for_each(|modification| {
+                cube_map_height.add_pixel(
+                    &modification.face,
+                    modification.x,
+                    modification.y,
+                    modification.delta,
+                );
+            });
+            println!("Erosion iteration: {}/{iterations}", iteration + 1);
+        }
+        return;
+    }
 
-                if (droplet.velocity.length() < 0.01) {
-                    cube_map_height.add(smooth_normal, droplet.accumulation);
-                    break;
-                }
-            }
+    let atomic_height = cube_map_height.to_atomic();
+    let finished_iters = Arc::new(Mutex::from(0_i32));
+    (0..iterations).into_par_iter().for_each(|iteration| {
+        for droplet_num in 0..droplets_per_iteration {
+            run_droplet(
+                &atomic_height,
+                cube_map_biome,
+                &settings,
+                iteration,
+                droplet_num,
+                &mut |position, delta| atomic_height.add(position, delta),
+            );
         }
         let mut finished_iters = finished_iters.lock().unwrap();
         *finished_iters += 1;
         println!("Erosion iteration: {}/{iterations}", finished_iters);
-    })
+    });
+    cube_map_height.copy_from_atomic(&atomic_height);
 }
@@ -1,12 +1,13 @@
 use crate::base_icosphere::get_base_icosphere;
 use crate::cubemap_data::CubeMapDataLayer;
+use crate::cubemap_mips::CubeMapMips;
 use crate::generate_terrain::InterpolatedBiomeData;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use glam::DVec3;
 use rayon::iter::IndexedParallelIterator;
 use rayon::iter::ParallelIterator;
-use rayon::prelude::IntoParallelIterator;
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator};
 use std::fs::File;
 use std::io::Write;
 
@@ -25,7 +26,7 @@ fn subdivide_triangle(tri: &Triangle) -> [Triangle; 4] {
     ]
 }
 
-fn subdivide_triangle_multiple(tri: Triangle, count: u8) -> Vec<Triangle> {
+pub(crate) fn subdivide_triangle_multiple(tri: Triangle, count: u8) -> Vec<Triangle> {
     let mut triangles = vec![tri];
     for i in 0..count {
         let mut tmp: Vec<Triangle> = vec![];
@@ -45,15 +46,23 @@ fn normalize_triangle(tri: &Triangle) -> Triangle {
     [tri[0].normalize(), tri[1].normalize(), tri[2].normalize()]
 }
 
-fn scale_vector(v: DVec3, input: &CubeMapDataLayer<f64>) -> DVec3 {
-    v * input.get_bilinear(v)
+// Nominal edge length on the unit sphere after subdivisions halvings of the base icosphere, the
+// area one vertex stands for. The same for every triangle of a level, so a vertex shared by
+// several triangles gets the same filtered height from each and the mesh has no cracks
+pub fn get_level_footprint(subdivisions: u32) -> f64 {
+    let base = get_base_icosphere()[0];
+    base[0].normalize().distance(base[1].normalize()) / 2f64.powi(subdivisions as i32)
 }
 
-fn scale_triangle(tri: &Triangle, input: &CubeMapDataLayer<f64>) -> Triangle {
+fn scale_vector(v: DVec3, input: &CubeMapMips, footprint: f64) -> DVec3 {
+    v * input.get_trilinear(v, footprint)
+}
+
+fn scale_triangle(tri: &Triangle, input: &CubeMapMips, footprint: f64) -> Triangle {
     [
-        scale_vector(tri[0], input),
-        scale_vector(tri[1], input),
-        scale_vector(tri[2], input),
+        scale_vector(tri[0], input, footprint),
+        scale_vector(tri[1], input, footprint),
+        scale_vector(tri[2], input, footprint),
     ]
 }
 
@@ -73,14 +82,74 @@ fn get_triangle_normal(tri: &Triangle) -> DVec3 {
     (tri[1] - tri[0]).cross(tri[2] - tri[0]).normalize()
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VertexAttributeFormat {
+    Float32,
+    Uint8,
+    Uint16,
+}
+
+// stored value = value * scale, so a loader can map quantized values back
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexAttribute {
+    pub name: String,
+    pub format: VertexAttributeFormat,
+    pub components: u8,
+    pub offset: u16,
+    pub scale: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VertexLayout {
+    pub name: String,
+    pub stride: u16,
+    pub attributes: Vec<VertexAttribute>,
+}
+
+fn vertex_attribute(
+    name: &str,
+    format: VertexAttributeFormat,
+    components: u8,
+    offset: u16,
+    scale: f32,
+) -> VertexAttribute {
+    VertexAttribute {
+        name: name.to_string(),
+        format,
+        components,
+        offset,
+        scale,
+    }
+}
+
 /*
-Terrain layout is:
-    position: vec3
-    normal: vec3
-    color: vec3
-    roughness: float
-    global_index: uint32
+Terrain layout is (24 bytes):
+    position: vec3 f32
+    normal: vec3 u8 (* 127)
+    biome_blend: u8 (* 255), weight of the second biome
+    color: vec3 u8 (* 255)
+    roughness: u8 (* 255)
+    global_index: u16
+    biome_id: u8
+    second_biome_id: u8
 */
+pub fn terrain_vertex_layout() -> VertexLayout {
+    VertexLayout {
+        name: "terrain".to_string(),
+        stride: 24,
+        attributes: vec![
+            vertex_attribute("position", VertexAttributeFormat::Float32, 3, 0, 1.0),
+            vertex_attribute("normal", VertexAttributeFormat::Uint8, 3, 12, 127.0),
+            vertex_attribute("biome_blend", VertexAttributeFormat::Uint8, 1, 15, 255.0),
+            vertex_attribute("color", VertexAttributeFormat::Uint8, 3, 16, 255.0),
+            vertex_attribute("roughness", VertexAttributeFormat::Uint8, 1, 19, 255.0),
+            vertex_attribute("global_index", VertexAttributeFormat::Uint16, 1, 20, 1.0),
+            vertex_attribute("biome_id", VertexAttributeFormat::Uint8, 1, 22, 1.0),
+            vertex_attribute("second_biome_id", VertexAttributeFormat::Uint8, 1, 23, 1.0),
+        ],
+    }
+}
+
 fn write_vector_terrain(
     file: &mut dyn Write,
     v: DVec3,
@@ -101,7 +170,7 @@ fn write_vector_terrain(
         .expect("Write failed");
     file.write_all(&((n.z * 127.0) as u8).to_le_bytes())
         .expect("Write failed");
-    file.write_all(&((0) as u8).to_le_bytes())
+    file.write_all(&((interpolated_biome_data.second_weight * 255.0) as u8).to_le_bytes())
         .expect("Write failed");
 
     file.write_all(&((interpolated_biome_data.color.x * 255.0) as u8).to_le_bytes())
@@ -115,55 +184,52 @@ fn write_vector_terrain(
 
     file.write_all(&(global_index as u16).to_le_bytes())
         .expect("Write failed");
-    file.write_all(&((0) as u16).to_le_bytes())
-        .expect("Write failed");
+    file.write_all(&[
+        interpolated_biome_data.dominating_id,
+        interpolated_biome_data.second_id,
+    ])
+    .expect("Write failed");
 }
 
 fn write_triangle_terrain(
-    height_data: &CubeMapDataLayer<f64>,
+    height_data: &CubeMapMips,
     biome_data: &CubeMapDataLayer<InterpolatedBiomeData>,
     file: &mut dyn Write,
     tri: &Triangle,
     norm_tri: &Triangle,
+    footprint: f64,
     global_index: u32,
 ) {
-    write_vector_terrain(
-        file,
-        tri[0],
-        height_data.get_normal(
-            norm_tri[0],
-            height_data.get_pixel_distance_for_dir(norm_tri[0]),
-        ),
-        biome_data.get(norm_tri[0]),
-        global_index,
-    );
-    write_vector_terrain(
-        file,
-        tri[1],
-        height_data.get_normal(
-            norm_tri[1],
-            height_data.get_pixel_distance_for_dir(norm_tri[1]),
-        ),
-        biome_data.get(norm_tri[1]),
-        global_index,
-    );
-    write_vector_terrain(
-        file,
-        tri[2],
-        height_data.get_normal(
-            norm_tri[2],
-            height_data.get_pixel_distance_for_dir(norm_tri[2]),
-        ),
-        biome_data.get(norm_tri[2]),
-        global_index,
-    );
+    for i in 0..3 {
+        let dir = norm_tri[i];
+        // never below a pixel, finer levels keep the normals they always had
+        let dxrange = footprint.max(height_data.level(0).get_pixel_distance_for_dir(dir));
+        write_vector_terrain(
+            file,
+            tri[i],
+            height_data.get_normal(dir, dxrange),
+            biome_data.get(dir),
+            global_index,
+        );
+    }
 }
 
 /*
-Water layout is just:
-    position: vec3
-    global_index: uint32
+Water layout is just (16 bytes):
+    position: vec3 f32
+    global_index: u16, 2 bytes padding
 */
+pub fn water_vertex_layout() -> VertexLayout {
+    VertexLayout {
+        name: "water".to_string(),
+        stride: 16,
+        attributes: vec![
+            vertex_attribute("position", VertexAttributeFormat::Float32, 3, 0, 1.0),
+            vertex_attribute("global_index", VertexAttributeFormat::Uint16, 1, 12, 1.0),
+        ],
+    }
+}
+
 fn write_vector_water(file: &mut dyn Write, v: DVec3, global_index: u32) {
     file.write_all(&(v.x as f32).to_le_bytes())
         .expect("Write failed");
@@ -184,8 +250,49 @@ fn write_triangle_water(file: &mut dyn Write, tri: &Triangle, global_index: u32)
     write_vector_water(file, tri[2], global_index);
 }
 
-pub fn generate_icosphere_raw(
-    output_dir: &str,
+pub struct IcosphereChunk {
+    pub index_main: usize,
+    pub index: usize,
+    pub center: DVec3,
+    // vertex records in the terrain or water layout described above, one buffer per LOD level
+    pub level1: Vec<u8>,
+    pub level2: Vec<u8>,
+    pub level3: Vec<u8>,
+}
+
+fn write_level(
+    output: &mut dyn Write,
+    triangles: Vec<Triangle>,
+    height_data: &CubeMapMips,
+    biome_data: Option<&CubeMapDataLayer<InterpolatedBiomeData>>,
+    footprint: f64,
+    part_center: DVec3,
+    global_index: u32,
+) {
+    triangles.iter().for_each(|t| {
+        let t = normalize_triangle(t);
+        let vec0dir = t[0].normalize();
+        let vec1dir = t[1].normalize();
+        let vec2dir = t[2].normalize();
+        let directions_triangle: Triangle = [vec0dir, vec1dir, vec2dir];
+        let t = scale_triangle(&t, height_data, footprint);
+        let t = translate_triangle(&t, -part_center);
+        match biome_data {
+            None => write_triangle_water(output, &t, global_index),
+            Some(biome_data) => write_triangle_terrain(
+                height_data,
+                biome_data,
+                output,
+                &t,
+                &directions_triangle,
+                footprint,
+                global_index,
+            ),
+        }
+    });
+}
+
+pub fn generate_icosphere(
     height_data: &CubeMapDataLayer<f64>,
     biome_data: Option<&CubeMapDataLayer<InterpolatedBiomeData>>,
     sphere_radius: f64,
@@ -193,154 +300,134 @@ pub fn generate_icosphere_raw(
     subdivide_level1: u8,
     subdivide_level2: u8,
     subdivide_level3: u8,
-) {
+) -> Vec<IcosphereChunk> {
     let base = get_base_icosphere();
+    // the coarse levels sample a filtered height instead of aliasing single pixels
+    let height_mips = height_data.build_mips();
 
-    let mut metadata_file =
-        File::create(output_dir.to_owned() + "/metadata.ini").expect("create failed");
-
+    let mut chunks = vec![];
     base.into_iter()
         .enumerate()
         .for_each(|(index_main, triangle)| {
             println!("{index_main}/{}", base.len());
-            let mut level0 = subdivide_triangle_multiple(triangle, subdivide_initial);
+            let level0 = subdivide_triangle_multiple(triangle, subdivide_initial);
 
-            // not parallel to not worry about borrow checker
-            level0
-                .clone()
-                .into_iter()
+            let level0_len = level0.len();
+            let mut level0_chunks: Vec<IcosphereChunk> = level0
+                .into_par_iter()
                 .enumerate()
-                .for_each(|(index, t)| {
+                .map(|(index, t)| {
                     let part_center = get_triangle_center(&t, sphere_radius);
-                    let data = format!(
-                        "{index_main}-{index}={},{},{}\n",
-                        part_center.x, part_center.y, part_center.z
+                    let global_index = (index_main * level0_len) as u32 + index as u32;
+
+                    let mut chunk = IcosphereChunk {
+                        index_main,
+                        index,
+                        center: part_center,
+                        level1: vec![],
+                        level2: vec![],
+                        level3: vec![],
+                    };
+                    write_level(
+                        &mut chunk.level1,
+                        subdivide_triangle_multiple(t, subdivide_level1),
+                        &height_mips,
+                        biome_data,
+                        get_level_footprint(subdivide_initial as u32 + subdivide_level1 as u32),
+                        part_center,
+                        global_index,
                     );
-                    metadata_file.write(data.as_bytes()).expect("Write failed");
-                });
+                    write_level(
+                        &mut chunk.level2,
+                        subdivide_triangle_multiple(t, subdivide_level2),
+                        &height_mips,
+                        biome_data,
+                        get_level_footprint(subdivide_initial as u32 + subdivide_level2 as u32),
+                        part_center,
+                        global_index,
+                    );
+                    write_level(
+                        &mut chunk.level3,
+                        subdivide_triangle_multiple(t, subdivide_level3),
+                        &height_mips,
+                        biome_data,
+                        get_level_footprint(subdivide_initial as u32 + subdivide_level3 as u32),
+                        part_center,
+                        global_index,
+                    );
+                    chunk
+                })
+                .collect();
+            chunks.append(&mut level0_chunks);
+        });
+    chunks
+}
 
-            let level0_len = level0.len();
-            level0.into_par_iter().enumerate().for_each(|(index, t)| {
-                let mut level1file = brotli::CompressorWriter::new(
-                    File::create(
-                        output_dir.to_owned()
-                            + "/"
-                            + (index_main).to_string().as_str()
-                            + "-"
-                            + (index).to_string().as_str()
-                            + ".l1.raw",
-                    )
-                    .expect("create failed"),
-                    40960,
-                    11,
-                    21,
-                );
-
-                let mut level2file = brotli::CompressorWriter::new(
-                    File::create(
-                        output_dir.to_owned()
-                            + "/"
-                            + (index_main).to_string().as_str()
-                            + "-"
-                            + (index).to_string().as_str()
-                            + ".l2.raw",
-                    )
-                    .expect("create failed"),
-                    40960,
-                    11,
-                    21,
-                );
-
-                let mut level3file = brotli::CompressorWriter::new(
-                    File::create(
-                        output_dir.to_owned()
-                            + "/"
-                            + (index_main).to_string().as_str()
-                            + "-"
-                            + (index).to_string().as_str()
-                            + ".l3.raw",
-                    )
-                    .expect("create failed"),
-                    40960,
-                    11,
-                    21,
-                );
-
-                let part_center = get_triangle_center(&t, sphere_radius);
-
-                let mut level1 = subdivide_triangle_multiple(t, subdivide_level1);
-                let mut level2 = subdivide_triangle_multiple(t, subdivide_level2);
-                let mut level3 = subdivide_triangle_multiple(t, subdivide_level3);
-
-                let global_index = (index_main * level0_len) as u32 + index as u32;
-
-                level1.iter_mut().for_each(|t| {
-                    let t = normalize_triangle(&t);
-                    let vec0dir = t[0].clone().normalize();
-                    let vec1dir = t[1].clone().normalize();
-                    let vec2dir = t[2].clone().normalize();
-                    let directions_triangle: Triangle = [vec0dir, vec1dir, vec2dir];
-                    let t = scale_triangle(&t, height_data);
-                    let t = translate_triangle(&t, -part_center);
-                    match biome_data {
-                        None => write_triangle_water(&mut level1file, &t, global_index),
-                        Some(biome_data) => write_triangle_terrain(
-                            &height_data,
-                            &biome_data,
-                            &mut level1file,
-                            &t,
-                            &directions_triangle,
-                            global_index,
-                        ),
-                    }
-                });
+fn save_level_raw(path: String, data: &[u8]) {
+    let mut file =
+        brotli::CompressorWriter::new(File::create(path).expect("create failed"), 40960, 11, 21);
+    file.write_all(data).expect("Write failed");
+    file.flush().unwrap();
+}
 
-                level2.iter_mut().for_each(|t| {
-                    let t = normalize_triangle(&t);
-                    let vec0dir = t[0].clone().normalize();
-                    let vec1dir = t[1].clone().normalize();
-                    let vec2dir = t[2].clone().normalize();
-                    let directions_triangle: Triangle = [vec0dir, vec1dir, vec2dir];
-                    let t = scale_triangle(&t, height_data);
-                    let t = translate_triangle(&t, -part_center);
-                    match biome_data {
-                        None => write_triangle_water(&mut level2file, &t, global_index),
-                        Some(biome_data) => write_triangle_terrain(
-                            &height_data,
-                            &biome_data,
-                            &mut level2file,
-                            &t,
-                            &directions_triangle,
-                            global_index,
-                        ),
-                    }
-                });
+pub fn save_icosphere_raw(output_dir: &str, chunks: &[IcosphereChunk]) {
+    let mut metadata_file =
+        File::create(output_dir.to_owned() + "/metadata.ini").expect("create failed");
+
+    chunks.iter().for_each(|chunk| {
+        let data = format!(
+            "{}-{}={},{},{}\n",
+            chunk.index_main, chunk.index, chunk.center.x, chunk.center.y, chunk.center.z
+        );
+        metadata_file
+            .write_all(data.as_bytes())
+            .expect("Write failed");
+    });
+    metadata_file.flush().unwrap();
+
+    chunks.par_iter().for_each(|chunk| {
+        let prefix = format!("{}/{}-{}", output_dir, chunk.index_main, chunk.index);
+        save_level_raw(prefix.clone() + ".l1.raw", &chunk.level1);
+        save_level_raw(prefix.clone() + ".l2.raw", &chunk.level2);
+        save_level_raw(prefix + ".l3.raw", &chunk.level3);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cubemap_data::CubeMapFace;
+    use std::collections::HashMap;
 
-                level3.iter_mut().for_each(|t| {
-                    let t = normalize_triangle(&t);
-                    let vec0dir = t[0].clone().normalize();
-                    let vec1dir = t[1].clone().normalize();
-                    let vec2dir = t[2].clone().normalize();
-                    let directions_triangle: Triangle = [vec0dir, vec1dir, vec2dir];
-                    let t = scale_triangle(&t, height_data);
-                    let t = translate_triangle(&t, -part_center);
-                    // println!("DIST {}", (t[0] - t[1]).length());
-                    match biome_data {
-                        None => write_triangle_water(&mut level3file, &t, global_index),
-                        Some(biome_data) => write_triangle_terrain(
-                            &height_data,
-                            &biome_data,
-                            &mut level3file,
-                            &t,
-                            &directions_triangle,
-                            global_index,
-                        ),
-                    }
+    #[test]
+    fn test_shared_vertices_get_the_same_height() {
+        const RES: u32 = 64;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        // detail at the pixel scale, so every mip level gives a different height
+        height.par_map_pixels(|_: &CubeMapFace, x, y, _| {
+            1000.0 + ((x * 7 + y * 13) % 5) as f64 * 10.0
+        });
+        let chunks = generate_icosphere(&height, None, 1000.0, 1, 1, 2, 3);
+
+        for level in 0..3 {
+            let mut heights: HashMap<[i64; 3], Vec<f64>> = HashMap::new();
+            chunks.iter().for_each(|chunk| {
+                let data = [&chunk.level1, &chunk.level2, &chunk.level3][level];
+                data.chunks_exact(16).for_each(|vertex| {
+                    let float = |i: usize| {
+                        f32::from_le_bytes(vertex[i * 4..i * 4 + 4].try_into().unwrap()) as f64
+                    };
+                    let position = DVec3::new(float(0), float(1), float(2)) + chunk.center;
+                    let key = (position.normalize() * 1e5).round().as_i64vec3().to_array();
+                    heights.entry(key).or_default().push(position.length());
                 });
-                level1file.flush().unwrap();
-                level2file.flush().unwrap();
-                level3file.flush().unwrap();
             });
-        });
-    metadata_file.flush().unwrap();
+            assert!(heights.values().any(|shared| shared.len() > 1));
+            heights.values().for_each(|shared| {
+                shared
+                    .iter()
+                    .for_each(|value| assert!((value - shared[0]).abs() < 1e-3));
+            });
+        }
+    }
 }
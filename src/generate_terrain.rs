@@ -1,24 +1,33 @@
-use crate::craters::add_craters;
-use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
-use crate::erosion::erosion_run;
-use crate::generate_icosphere::generate_icosphere_raw;
+use crate::cubemap_data::{face_pixel_to_direction, CubeMapDataLayer, CubeMapFace};
+use crate::cubemap_storage::LayerStorage;
+use crate::domain_warp::DomainWarp;
 use crate::json_input::{
     InputBiome, InputBiomeModifier, InputCelestialBodyDefinition, InputTerrain, InputVector3,
 };
 use crate::math_util::{map, mix, usat};
-use crate::noise::fbm;
-use crate::save_binary_maps::save_terrain_maps;
+use crate::noise::{fbm, fbm_with, noise_function, Fractal, NoiseFunction};
+use crate::random::{
+    derive_seed, master_seed, SEED_STREAM_BIOME_MODIFIER, SEED_STREAM_BIOME_RANDOMIZER,
+    SEED_STREAM_BIOME_WARP, SEED_STREAM_HEIGHT, SEED_STREAM_HEIGHT_WARP,
+};
+use crate::save_binary_maps::save_terrain_height_maps;
+use crate::terrain_stages::{
+    build_terrain_stages, default_terrain_stages, run_terrain_stages, StageContext,
+};
 use glam::{DVec3, Vec3};
+use image::RgbaImage;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
-use std::fs;
-use std::io::ErrorKind;
 use std::path::Path;
 use std::time::Instant;
 
 #[derive(Clone)]
 pub struct InterpolatedBiomeData {
-    //   pub dominating_id: u32,
+    // the biome with the highest fitness and the runner up, blended by second_weight which is
+    // the runner up fitness relative to both, so it stays in 0..0.5
+    pub dominating_id: u8,
+    pub second_id: u8,
+    pub second_weight: f32,
     pub color: Vec3,
     pub roughness: f32,
     pub erosion_strength: f32,
@@ -28,224 +37,329 @@ pub struct InterpolatedBiomeData {
     pub max_crater_size: f32,
 }
 
-fn generate_biomes(
+// Everything needed to rate how well each biome fits a pixel
+struct BiomeFitness<'a> {
+    terrain: &'a InputTerrain,
+    modifier_seed: u64,
+    biome_seeds: Vec<u64>,
+    randomizers: Vec<Box<dyn NoiseFunction>>,
+    warp: DomainWarp,
+}
+
+impl BiomeFitness<'_> {
+    fn new(terrain: &InputTerrain) -> BiomeFitness<'_> {
+        let seed = master_seed(terrain.terrain_generation.seed);
+        let randomizer_seed = derive_seed(seed, SEED_STREAM_BIOME_RANDOMIZER);
+        BiomeFitness {
+            terrain,
+            modifier_seed: derive_seed(seed, SEED_STREAM_BIOME_MODIFIER),
+            biome_seeds: terrain
+                .biomes
+                .iter()
+                .enumerate()
+                .map(|(index, biome)| {
+                    derive_seed(
+                        derive_seed(randomizer_seed, index as u64),
+                        biome.seed.to_bits(),
+                    )
+                })
+                .collect(),
+            randomizers: terrain
+                .biomes
+                .iter()
+                .map(|biome| noise_function(biome.randomizer_noise))
+                .collect(),
+            warp: DomainWarp::new(
+                &terrain.terrain_generation.biome_warp,
+                terrain.terrain_generation.noise,
+                derive_seed(seed, SEED_STREAM_BIOME_WARP),
+            ),
+        }
+    }
+
+    // Fitness of every biome in the input order, height is relative to the radius
+    fn fitness(&self, dir: DVec3, height: f64) -> impl Iterator<Item = f64> + '_ {
+        let warped = self.warp.warp(dir);
+        let modifier = match self.terrain.biome_modifier {
+            InputBiomeModifier::Latitude => dir.y.abs() * 90.0,
+            InputBiomeModifier::Tidal => -dir.z, // so by default -z faces the star
+            InputBiomeModifier::Random => fbm(warped, self.modifier_seed, 4, 2.0, 0.5),
+        };
+
+        self.terrain
+            .biomes
+            .iter()
+            .zip(&self.biome_seeds)
+            .zip(&self.randomizers)
+            .map(move |((biome, biome_seed), randomizer)| {
+                let fitness_altitude = usat(map(
+                    height,
+                    biome.min_altitude,
+                    biome.max_altitude,
+                    0.0,
+                    1.0,
+                ));
+                let fitness_modifier = usat(map(
+                    modifier,
+                    biome.min_modifier,
+                    biome.max_modifier,
+                    0.0,
+                    1.0,
+                ));
+
+                let randomizer =
+                    fbm_with(randomizer.as_ref(), warped * 4.0, *biome_seed, 5, 2.0, 0.5);
+
+                fitness_altitude * fitness_modifier * (0.5 + 0.5 * randomizer) + 0.001
+            })
+    }
+}
+
+pub(crate) fn generate_biomes(
     input: &InputCelestialBodyDefinition,
     terrain: &InputTerrain,
     cube_map_height: &CubeMapDataLayer<f64>,
-    cube_map_biome: &CubeMapDataLayer<InterpolatedBiomeData>,
+    cube_map_biome: &mut CubeMapDataLayer<InterpolatedBiomeData>,
 ) {
     let cube_map_res = input.generator_config.cube_map_resolution;
+    let biome_fitness = BiomeFitness::new(terrain);
 
-    let mutable_biome = [
-        (
-            CubeMapFace::PX,
-            cube_map_biome.get_mutable_face(&CubeMapFace::PX),
-            cube_map_height.get_mutable_face(&CubeMapFace::PX),
-        ),
-        (
-            CubeMapFace::PY,
-            cube_map_biome.get_mutable_face(&CubeMapFace::PY),
-            cube_map_height.get_mutable_face(&CubeMapFace::PY),
-        ),
-        (
-            CubeMapFace::PZ,
-            cube_map_biome.get_mutable_face(&CubeMapFace::PZ),
-            cube_map_height.get_mutable_face(&CubeMapFace::PZ),
-        ),
-        (
-            CubeMapFace::NX,
-            cube_map_biome.get_mutable_face(&CubeMapFace::NX),
-            cube_map_height.get_mutable_face(&CubeMapFace::NX),
-        ),
-        (
-            CubeMapFace::NY,
-            cube_map_biome.get_mutable_face(&CubeMapFace::NY),
-            cube_map_height.get_mutable_face(&CubeMapFace::NY),
-        ),
-        (
-            CubeMapFace::NZ,
-            cube_map_biome.get_mutable_face(&CubeMapFace::NZ),
-            cube_map_height.get_mutable_face(&CubeMapFace::NZ),
-        ),
-    ];
+    println!("Generating terrain biomes, res: {}", cube_map_res);
+    cube_map_biome.par_zip_pixels(cube_map_height, |_, _, _, dir, height| {
+        let height = height - terrain.radius;
 
-    mutable_biome.into_par_iter().for_each(|face| {
-        println!(
-            "Generating terrain biome face {}, res: {}",
-            face.0, cube_map_res
-        );
-        let mut face_data = face.1.lock().unwrap();
-        let mut height_face_data = face.2.lock().unwrap();
-        for y in (0..cube_map_res) {
-            for x in (0..cube_map_res) {
-                let dir = cube_map_biome.pixel_coords_to_direction(&face.0, x as usize, y as usize);
-                let index = (y as usize) * (cube_map_res as usize) + (x as usize);
-
-                let height = height_face_data[index] - terrain.radius;
-
-                let modifier = match terrain.biome_modifier {
-                    InputBiomeModifier::Latitude => dir.y.abs() * 90.0,
-                    InputBiomeModifier::Tidal => -dir.z, // so by default -z faces the star
-                    InputBiomeModifier::Random => fbm(dir, 4, 2.0, 0.5),
-                };
-
-                let mut result = InterpolatedBiomeData {
-                    //dominating_id: 0,
-                    color: Vec3::new(0.0, 0.0, 0.0),
-                    roughness: 0.0,
-                    erosion_strength: 0.0,
-                    deposition_strength: 0.0,
-                    craters_probability: 0.0,
-                    min_crater_size: 0.0,
-                    max_crater_size: 0.0,
-                };
-                let mut sum: f32 = 0.0;
-
-                let mut randomseed = 0.0;
-
-                terrain.biomes.iter().for_each(|biome| {
-                    let fitness_altitude = usat(map(
-                        height,
-                        biome.min_altitude,
-                        biome.max_altitude,
-                        0.0,
-                        1.0,
-                    ));
-                    let fitness_modifier = usat(map(
-                        modifier,
-                        biome.min_modifier,
-                        biome.max_modifier,
-                        0.0,
-                        1.0,
-                    ));
-
-                    let randomizer = fbm(dir * 4.0 + randomseed + biome.seed, 5, 2.0, 0.5);
-                    randomseed += 123.0;
-
-                    let fitness =
-                        fitness_altitude * fitness_modifier * (0.5 + 0.5 * randomizer) + 0.001;
-
-                    // result.dominating_id += biome.id; //TODO :(
-                    result.color += Vec3::new(
-                        biome.color.x as f32,
-                        biome.color.y as f32,
-                        biome.color.z as f32,
-                    ) * fitness as f32;
-                    result.roughness += (biome.roughness * fitness) as f32;
-                    result.erosion_strength += (biome.erosion_strength * fitness) as f32;
-                    result.deposition_strength += (biome.deposition_strength * fitness) as f32;
-
-                    result.craters_probability += (biome.craters_probability * fitness) as f32;
-                    result.min_crater_size += (biome.min_crater_size * fitness) as f32;
-                    result.max_crater_size += (biome.max_crater_size * fitness) as f32;
-
-                    sum += fitness as f32;
-                });
-
-                if sum > 0.0 {
-                    //  result.dominating_id = (result.dominating_id as f64 / sum) as u32;
-                    result.color = result.color / sum;
-                    result.roughness = result.roughness / sum;
-                    result.erosion_strength = result.erosion_strength / sum;
-                    result.deposition_strength = result.deposition_strength / sum;
-
-                    result.craters_probability = result.craters_probability / sum;
-                    result.min_crater_size = result.min_crater_size / sum;
-                    result.max_crater_size = result.max_crater_size / sum;
+        let mut result = InterpolatedBiomeData {
+            dominating_id: 0,
+            second_id: 0,
+            second_weight: 0.0,
+            color: Vec3::new(0.0, 0.0, 0.0),
+            roughness: 0.0,
+            erosion_strength: 0.0,
+            deposition_strength: 0.0,
+            craters_probability: 0.0,
+            min_crater_size: 0.0,
+            max_crater_size: 0.0,
+        };
+        let mut sum: f32 = 0.0;
+        // (id, fitness) of the best two biomes
+        let mut top = [(0, f64::MIN), (0, f64::MIN)];
+
+        terrain
+            .biomes
+            .iter()
+            .zip(biome_fitness.fitness(dir, height))
+            .for_each(|(biome, fitness)| {
+                if fitness > top[0].1 {
+                    top[1] = top[0];
+                    top[0] = (biome.id, fitness);
+                } else if fitness > top[1].1 {
+                    top[1] = (biome.id, fitness);
                 }
 
-                face_data[index] = result;
-            }
+                result.color += Vec3::new(
+                    biome.color.x as f32,
+                    biome.color.y as f32,
+                    biome.color.z as f32,
+                ) * fitness as f32;
+                result.roughness += (biome.roughness * fitness) as f32;
+                result.erosion_strength += (biome.erosion_strength * fitness) as f32;
+                result.deposition_strength += (biome.deposition_strength * fitness) as f32;
+
+                result.craters_probability += (biome.craters_probability * fitness) as f32;
+                result.min_crater_size += (biome.min_crater_size * fitness) as f32;
+                result.max_crater_size += (biome.max_crater_size * fitness) as f32;
+
+                sum += fitness as f32;
+            });
+
+        result.dominating_id = top[0].0;
+        if top[1].1 > 0.0 {
+            result.second_id = top[1].0;
+            result.second_weight = (top[1].1 / (top[0].1 + top[1].1)) as f32;
+        } else {
+            // only one biome
+            result.second_id = top[0].0;
         }
+
+        if sum > 0.0 {
+            result.color = result.color / sum;
+            result.roughness = result.roughness / sum;
+            result.erosion_strength = result.erosion_strength / sum;
+            result.deposition_strength = result.deposition_strength / sum;
+
+            result.craters_probability = result.craters_probability / sum;
+            result.min_crater_size = result.min_crater_size / sum;
+            result.max_crater_size = result.max_crater_size / sum;
+        }
+
+        result
     });
 }
 
-fn generate_height(
-    input: &InputCelestialBodyDefinition,
+// The weights the biome layer is blended from, for the height it was made from. Kept next to the
+// biome layer so the splat maps still match it when later stages change the height
+pub(crate) fn generate_splat_weights(
     terrain: &InputTerrain,
     cube_map_height: &CubeMapDataLayer<f64>,
+) -> Vec<CubeMapDataLayer<[u8; 4]>> {
+    let biome_fitness = BiomeFitness::new(terrain);
+    (0..terrain.biomes.len().div_ceil(4))
+        .map(|layer| {
+            println!(
+                "Generating splat weights {}, res: {}",
+                layer, cube_map_height.res
+            );
+            let mut splat =
+                CubeMapDataLayer::new_in(cube_map_height.res, [0; 4], cube_map_height.storage());
+            splat.par_zip_pixels(cube_map_height, |_, _, _, dir, height| {
+                let fitness: Vec<f64> = biome_fitness
+                    .fitness(dir, height - terrain.radius)
+                    .collect();
+                let sum: f64 = fitness.iter().sum();
+                std::array::from_fn(|channel| {
+                    fitness
+                        .get(layer * 4 + channel)
+                        .map_or(0, |fitness| (fitness / sum * 255.0).round() as u8)
+                })
+            });
+            splat
+        })
+        .collect()
+}
+
+pub(crate) fn generate_height(
+    input: &InputCelestialBodyDefinition,
+    terrain: &InputTerrain,
+    cube_map_height: &mut CubeMapDataLayer<f64>,
 ) {
     let cube_map_res = input.generator_config.cube_map_resolution;
-
-    let mutable_height = [
-        (
-            CubeMapFace::PX,
-            cube_map_height.get_mutable_face(&CubeMapFace::PX),
-        ),
-        (
-            CubeMapFace::PY,
-            cube_map_height.get_mutable_face(&CubeMapFace::PY),
-        ),
-        (
-            CubeMapFace::PZ,
-            cube_map_height.get_mutable_face(&CubeMapFace::PZ),
-        ),
-        (
-            CubeMapFace::NX,
-            cube_map_height.get_mutable_face(&CubeMapFace::NX),
-        ),
-        (
-            CubeMapFace::NY,
-            cube_map_height.get_mutable_face(&CubeMapFace::NY),
-        ),
-        (
-            CubeMapFace::NZ,
-            cube_map_height.get_mutable_face(&CubeMapFace::NZ),
+    let seed = derive_seed(
+        master_seed(terrain.terrain_generation.seed),
+        SEED_STREAM_HEIGHT,
+    );
+    let noise = noise_function(terrain.terrain_generation.noise);
+    let fractal = Fractal::from_terrain_generation(&terrain.terrain_generation);
+    let warp = DomainWarp::new(
+        &terrain.terrain_generation.height_warp,
+        terrain.terrain_generation.noise,
+        derive_seed(
+            master_seed(terrain.terrain_generation.seed),
+            SEED_STREAM_HEIGHT_WARP,
         ),
-    ];
+    );
 
-    mutable_height.into_par_iter().for_each(|face| {
-        println!(
-            "Generating terrain height face {}, res: {}",
-            face.0, cube_map_res
-        );
-        let mut face_data = face.1.lock().unwrap();
-        for y in (0..cube_map_res) {
-            for x in (0..cube_map_res) {
-                let dir =
-                    cube_map_height.pixel_coords_to_direction(&face.0, x as usize, y as usize);
-                let value = if terrain.terrain_generation.fbm_iterations == 0 {
-                    0.0
-                } else {
-                    let unorm = fbm(
-                        dir * terrain.terrain_generation.fbm_scale
-                            + terrain.terrain_generation.seed,
-                        terrain.terrain_generation.fbm_iterations,
-                        terrain.terrain_generation.fbm_iteration_scale_coefficient,
-                        terrain.terrain_generation.fbm_iteration_weight_coefficient,
-                    )
-                    .powf(terrain.terrain_generation.fbm_final_power);
-                    mix(terrain.min_height, terrain.max_height, unorm)
-                };
-                let index = (y as usize) * (cube_map_res as usize) + (x as usize);
-                face_data[index] = terrain.radius + value;
-            }
-        }
+    println!("Generating terrain height, res: {}", cube_map_res);
+    cube_map_height.par_map_pixels(|_, _, _, dir| {
+        let value = if terrain.terrain_generation.fbm_iterations == 0 {
+            0.0
+        } else {
+            let unorm = fractal
+                .sample(
+                    noise.as_ref(),
+                    warp.warp(dir) * terrain.terrain_generation.fbm_scale,
+                    seed,
+                )
+                .powf(terrain.terrain_generation.fbm_final_power);
+            mix(terrain.min_height, terrain.max_height, unorm)
+        };
+        terrain.radius + value
     });
 }
 
-pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
-    let terrain_out_dir = Path::new(&input.generator_config.out_dir).join("terrain");
-    let terrain_icosphere_out_dir = Path::new(&input.generator_config.out_dir)
-        .join("terrain")
-        .join("icosphere");
-    match fs::remove_dir_all(&terrain_out_dir) {
-        Ok(_) => {}
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => (), // this is fine
-            _ => panic!("Failed to delete the directory because {}", e),
-        },
+pub struct TerrainLayers {
+    pub height: CubeMapDataLayer<f64>,
+    pub biome: CubeMapDataLayer<InterpolatedBiomeData>,
+    // Normalized per biome weights from the biomes stage that made the biome layer, biome i in
+    // channel i % 4 of layer i / 4. Only filled with generatorConfig.exportSplatMaps
+    pub splat: Vec<CubeMapDataLayer<[u8; 4]>>,
+}
+
+pub fn generate_terrain_layers(
+    input: &InputCelestialBodyDefinition,
+    terrain: &InputTerrain,
+) -> TerrainLayers {
+    let stages = match &terrain.stages {
+        Some(stages) => build_terrain_stages(stages),
+        None => build_terrain_stages(&default_terrain_stages()),
     }
+    .unwrap_or_else(|error| panic!("{error}"));
+
+    let cube_map_res = input.generator_config.cube_map_resolution;
+    let storage = LayerStorage::from_dir(input.generator_config.storage_dir.as_deref());
+    let mut layers = TerrainLayers {
+        height: CubeMapDataLayer::new_in(cube_map_res, 0.0, &storage),
+        biome: CubeMapDataLayer::new_in(
+            cube_map_res,
+            InterpolatedBiomeData {
+                dominating_id: 0,
+                second_id: 0,
+                second_weight: 0.0,
+                color: Vec3::new(0.0, 0.0, 0.0),
+                deposition_strength: 0.0,
+                erosion_strength: 0.0,
+                roughness: 1.0,
+                craters_probability: 0.0,
+                min_crater_size: 0.0,
+                max_crater_size: 0.0,
+            },
+            &storage,
+        ),
+        splat: vec![],
+    };
 
-    fs::create_dir_all(&terrain_out_dir);
-    fs::create_dir(&terrain_icosphere_out_dir);
+    run_terrain_stages(&StageContext { input, terrain }, &mut layers, &stages);
 
-    let terrain = &input.terrain;
-    if (terrain.is_none()) {
-        return;
+    layers
+}
+
+// What saving reads from the finished terrain, so the compact layers are saved as they are
+pub trait TerrainMaps: Sync {
+    fn res(&self) -> u32;
+    fn height_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> f64;
+    fn height_bilinear(&self, dir: DVec3) -> f64;
+    // filtered over a pixel
+    fn height_normal(&self, dir: DVec3) -> DVec3;
+    fn biome(&self, dir: DVec3) -> InterpolatedBiomeData;
+    fn biome_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> InterpolatedBiomeData;
+    fn splat(&self) -> &[CubeMapDataLayer<[u8; 4]>];
+}
+
+impl TerrainMaps for TerrainLayers {
+    fn res(&self) -> u32 {
+        self.height.res
+    }
+
+    fn height_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> f64 {
+        self.height.get_pixel(face, x, y)
     }
-    let terrain = terrain.as_ref().unwrap();
 
+    fn height_bilinear(&self, dir: DVec3) -> f64 {
+        self.height.get_bilinear(dir)
+    }
+
+    fn height_normal(&self, dir: DVec3) -> DVec3 {
+        self.height
+            .get_normal(dir, self.height.get_pixel_distance_for_dir(dir))
+    }
+
+    fn biome(&self, dir: DVec3) -> InterpolatedBiomeData {
+        self.biome.get(dir)
+    }
+
+    fn biome_pixel(&self, face: &CubeMapFace, x: usize, y: usize) -> InterpolatedBiomeData {
+        self.biome.get_pixel(face, x, y)
+    }
+
+    fn splat(&self) -> &[CubeMapDataLayer<[u8; 4]>] {
+        &self.splat
+    }
+}
+
+pub fn save_terrain_layers(
+    terrain_out_dir: &Path,
+    terrain: &InputTerrain,
+    layers: &dyn TerrainMaps,
+) {
     let faces = [
         CubeMapFace::PX,
         CubeMapFace::PY,
@@ -255,55 +369,15 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
         CubeMapFace::NZ,
     ];
 
-    let cube_map_res = input.generator_config.cube_map_resolution;
-    let mut cube_map_height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(cube_map_res, 0.0);
-    let mut cube_map_biome: CubeMapDataLayer<InterpolatedBiomeData> = CubeMapDataLayer::new(
-        cube_map_res,
-        InterpolatedBiomeData {
-            color: Vec3::new(0.0, 0.0, 0.0),
-            deposition_strength: 0.0,
-            erosion_strength: 0.0,
-            roughness: 1.0,
-            // dominating_id: 0,
-            craters_probability: 0.0,
-            min_crater_size: 0.0,
-            max_crater_size: 0.0,
-        },
-    );
-
-    generate_height(&input, &terrain, &cube_map_height);
-    generate_biomes(&input, &terrain, &cube_map_height, &cube_map_biome);
-
-    add_craters(
-        &mut cube_map_height,
-        &cube_map_biome,
-        terrain.radius,
-        terrain.terrain_generation.seed,
-        terrain.terrain_generation.craters_count,
-    );
-
-    erosion_run(
-        &mut cube_map_height,
-        &mut cube_map_biome,
-        input.generator_config.erosion_iterations,
-        input.generator_config.erosion_droplets_count,
-        terrain.radius,
-        input.generator_config.erosion_droplet_velocity_coefficient,
-        input
-            .generator_config
-            .erosion_droplet_evaporation_coefficient,
-    );
-
-    // remap biomes after erosion for some more realistic effect
-    generate_biomes(&input, &terrain, &cube_map_height, &cube_map_biome);
+    let cube_map_res = layers.res();
 
     faces.clone().into_par_iter().for_each(|face| {
         println!("Saving height face {}, res: {}", face, cube_map_res);
-        let mut imgbuf = image::ImageBuffer::new(cube_map_res as u32, cube_map_res as u32);
+        let mut imgbuf = image::ImageBuffer::new(cube_map_res, cube_map_res);
         imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
-            let dir = cube_map_height.pixel_coords_to_direction(&face, x as usize, y as usize);
+            let dir = face_pixel_to_direction(cube_map_res, &face, x as usize, y as usize);
             let value = map(
-                cube_map_height.get_bilinear(dir),
+                layers.height_bilinear(dir),
                 terrain.radius + terrain.min_height,
                 terrain.radius + terrain.max_height,
                 0.0,
@@ -314,11 +388,7 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
             *pixel = image::Luma([(value * 255.0) as u8]);
         });
         imgbuf
-            .save(
-                terrain_out_dir
-                    .clone()
-                    .join(format!("height_face_{}.png", face)),
-            )
+            .save(terrain_out_dir.join(format!("height_face_{}.png", face)))
             .unwrap();
         imgbuf
             .save(format!("cubemap_visualizer/public/face_{}.png", face))
@@ -327,11 +397,10 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
 
     faces.clone().into_par_iter().for_each(|face| {
         println!("Saving normal face {}, res: {}", face, cube_map_res);
-        let mut imgbuf = image::ImageBuffer::new(cube_map_res as u32, cube_map_res as u32);
+        let mut imgbuf = image::ImageBuffer::new(cube_map_res, cube_map_res);
         imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
-            let dir = cube_map_height.pixel_coords_to_direction(&face, x as usize, y as usize);
-            let value =
-                cube_map_height.get_normal(dir, cube_map_height.get_pixel_distance_for_dir(dir));
+            let dir = face_pixel_to_direction(cube_map_res, &face, x as usize, y as usize);
+            let value = layers.height_normal(dir);
 
             *pixel = image::Rgb([
                 (value.x * 255.0) as u8,
@@ -340,11 +409,7 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
             ]);
         });
         imgbuf
-            .save(
-                terrain_out_dir
-                    .clone()
-                    .join(format!("normal_face_{}.png", face)),
-            )
+            .save(terrain_out_dir.join(format!("normal_face_{}.png", face)))
             .unwrap();
         imgbuf
             .save(format!(
@@ -356,10 +421,10 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
 
     faces.clone().into_par_iter().for_each(|face| {
         println!("Saving biome face {}, res: {}", face, cube_map_res);
-        let mut imgbuf = image::ImageBuffer::new(cube_map_res as u32, cube_map_res as u32);
+        let mut imgbuf = image::ImageBuffer::new(cube_map_res, cube_map_res);
         imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
-            let dir = cube_map_biome.pixel_coords_to_direction(&face, x as usize, y as usize);
-            let value = cube_map_biome.get(dir);
+            let dir = face_pixel_to_direction(cube_map_res, &face, x as usize, y as usize);
+            let value = layers.biome(dir);
 
             *pixel = image::Rgb([
                 (value.color.x * 255.0) as u8,
@@ -368,32 +433,188 @@ pub fn generate_terrain(input: &InputCelestialBodyDefinition) {
             ]);
         });
         imgbuf
-            .save(
-                terrain_out_dir
-                    .clone()
-                    .join(format!("biome_face_{}.png", face)),
-            )
+            .save(terrain_out_dir.join(format!("biome_face_{}.png", face)))
             .unwrap();
         imgbuf
             .save(format!("cubemap_visualizer/public/biome_face_{}.png", face))
             .unwrap();
     });
 
-    save_terrain_maps(
+    // 8 bit index map of the dominating biome, not interpolated as ids cannot be blended
+    faces.clone().into_par_iter().for_each(|face| {
+        println!("Saving biome id face {}, res: {}", face, cube_map_res);
+        let mut imgbuf = image::ImageBuffer::new(cube_map_res, cube_map_res);
+        imgbuf.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+            let value = layers.biome_pixel(&face, x as usize, y as usize);
+            *pixel = image::Luma([value.dominating_id]);
+        });
+        imgbuf
+            .save(terrain_out_dir.join(format!("biome_id_face_{}.png", face)))
+            .unwrap();
+    });
+
+    save_terrain_height_maps(
         terrain_out_dir.to_str().unwrap(),
         terrain.radius - terrain.min_height,
-        &cube_map_height,
+        cube_map_res,
+        |face, x, y| layers.height_pixel(face, x, y),
     );
+}
 
-    println!("Saving terrain icosphere");
-    generate_icosphere_raw(
-        terrain_icosphere_out_dir.to_str().unwrap(),
-        &cube_map_height,
-        Some(&cube_map_biome),
-        terrain.radius,
-        input.generator_config.subdivide_initial,
-        input.generator_config.subdivide_level1,
-        input.generator_config.subdivide_level2,
-        input.generator_config.subdivide_level3,
-    );
+// Normalized per biome weights, four biomes per RGBA texture. Biome i goes into channel i % 4 of
+// splat_{i / 4}_face_*.png, following the order of the biomes in the input
+pub fn save_splat_maps(terrain_out_dir: &Path, splat: &[CubeMapDataLayer<[u8; 4]>]) {
+    let faces = [
+        CubeMapFace::PX,
+        CubeMapFace::PY,
+        CubeMapFace::PZ,
+        CubeMapFace::NX,
+        CubeMapFace::NY,
+        CubeMapFace::NZ,
+    ];
+
+    if splat.is_empty() {
+        println!("No biomes stage ran, so there are no splat maps to save");
+    }
+    splat.iter().enumerate().for_each(|(index, layer)| {
+        let res = layer.res;
+        faces.clone().into_par_iter().for_each(|face| {
+            println!("Saving splat map {} face {}, res: {}", index, face, res);
+            let mut texture = RgbaImage::new(res, res);
+            texture.enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+                *pixel = image::Rgba(layer.get_pixel(&face, x as usize, y as usize));
+            });
+            texture
+                .save(terrain_out_dir.join(format!("splat_{}_face_{}.png", index, face)))
+                .unwrap();
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_input::{parse_input_data, InputTerrainStage};
+
+    #[test]
+    fn test_dominating_biome_ids() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 16;
+        let terrain = input.terrain.as_ref().unwrap();
+
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(16, terrain.radius);
+        generate_height(&input, terrain, &mut height);
+        let mut biome: CubeMapDataLayer<InterpolatedBiomeData> = CubeMapDataLayer::new(
+            16,
+            InterpolatedBiomeData {
+                dominating_id: 255,
+                second_id: 255,
+                second_weight: 1.0,
+                color: Vec3::ZERO,
+                roughness: 0.0,
+                erosion_strength: 0.0,
+                deposition_strength: 0.0,
+                craters_probability: 0.0,
+                min_crater_size: 0.0,
+                max_crater_size: 0.0,
+            },
+        );
+        generate_biomes(&input, terrain, &height, &mut biome);
+
+        let mut seen = [false; 3];
+        for face in [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ] {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let value = biome.get_pixel(&face, x, y);
+                    assert!(value.dominating_id < 3 && value.second_id < 3);
+                    assert_ne!(value.dominating_id, value.second_id);
+                    assert!((0.0..=0.5).contains(&value.second_weight));
+                    seen[value.dominating_id as usize] = true;
+                }
+            }
+        }
+        // the latitude modifier has to give more than one biome a chance
+        assert!(seen.iter().filter(|seen| **seen).count() > 1);
+    }
+
+    #[test]
+    fn test_biome_ids_past_8_bits_fail_parsing() {
+        let json = include_str!("../test-input.json").replacen(r#""id": 1,"#, r#""id": 256,"#, 1);
+        let error = serde_json::from_str::<InputCelestialBodyDefinition>(&json)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("256"), "{error}");
+    }
+
+    #[test]
+    fn test_splat_maps_sum_to_one() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 8;
+        let terrain = input.terrain.as_ref().unwrap();
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(8, terrain.radius);
+        generate_height(&input, terrain, &mut height);
+
+        let dir = std::env::temp_dir().join(format!("planetgen_splat_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        save_splat_maps(&dir, &generate_splat_weights(terrain, &height));
+
+        // three biomes fit into a single texture, alpha is unused
+        let splat = image::open(dir.join("splat_0_face_NZ.png"))
+            .unwrap()
+            .to_rgba8();
+        assert!(!dir.join("splat_1_face_NZ.png").exists());
+        for pixel in splat.pixels() {
+            let sum = pixel[0] as i32 + pixel[1] as i32 + pixel[2] as i32;
+            assert!((sum - 255).abs() <= 2);
+            assert_eq!(pixel[3], 0);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_splat_weights_match_the_biome_layer() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 16;
+        input.generator_config.export_splat_maps = true;
+        // the height keeps changing after the biomes
+        input.terrain.as_mut().unwrap().stages = Some(vec![
+            InputTerrainStage::Height,
+            InputTerrainStage::Biomes,
+            InputTerrainStage::Smoothing {
+                iterations: 4,
+                strength: 1.0,
+            },
+        ]);
+        let terrain = input.terrain.as_ref().unwrap();
+        let layers = generate_terrain_layers(&input, terrain);
+        assert_eq!(layers.splat.len(), 1);
+
+        let biome_index = |id: u8| {
+            terrain
+                .biomes
+                .iter()
+                .position(|biome| biome.id == id)
+                .unwrap()
+        };
+        for face in [CubeMapFace::PX, CubeMapFace::NY, CubeMapFace::NZ] {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let biome = layers.biome.get_pixel(&face, x, y);
+                    let weights = layers.splat[0].get_pixel(&face, x, y).map(|w| w as f32);
+                    let dominating = weights[biome_index(biome.dominating_id)];
+                    let second = weights[biome_index(biome.second_id)];
+                    assert!(weights.iter().all(|weight| *weight <= dominating));
+                    // second_weight is the runner up relative to both, up to the 8 bit rounding
+                    assert!((second - biome.second_weight * (dominating + second)).abs() <= 1.0);
+                }
+            }
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::noise_layers::mask_layer_indices;
 use serde::{Deserialize, Serialize};
 
 // ATTENTION - some fields here are missing as those are not revelant for the planetgen, for example, atmo
@@ -29,6 +30,83 @@ pub struct InputColorModifier {
     pub influence: f64,
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputWorleyFeature {
+    // distance to the closest cell point
+    #[default]
+    F1,
+    // distance to the second closest cell point
+    F2,
+    // zero on the borders between cells, for cracks
+    F2MinusF1,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputDistanceMetric {
+    #[default]
+    Euclidean,
+    Manhattan,
+    Chebyshev,
+}
+
+// Plain names for the gradient and value noises, worley is an object with its options, like
+// { "worley": { "feature": "f2MinusF1", "metric": "manhattan" } }
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputNoiseType {
+    #[default]
+    Value,
+    Perlin,
+    Simplex,
+    #[serde(rename = "openSimplex2")]
+    OpenSimplex2,
+    #[serde(rename_all = "camelCase")]
+    Worley {
+        #[serde(default)]
+        feature: InputWorleyFeature,
+        #[serde(default)]
+        metric: InputDistanceMetric,
+    },
+}
+
+// How the octaves are combined, fbm is the plain weighted average
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputFractalType {
+    #[default]
+    Fbm,
+    Ridged,
+    Hybrid,
+    Billow,
+}
+
+fn default_fractal_offset() -> f64 {
+    1.0
+}
+
+fn default_fractal_gain() -> f64 {
+    2.0
+}
+
+fn default_warp_octaves() -> u8 {
+    4
+}
+
+// Moves the sample position by up to amplitude (on the unit sphere) along an fbm of the position
+// times frequency, warps in a list apply one after another
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDomainWarp {
+    pub amplitude: f64,
+    pub frequency: f64,
+    #[serde(default = "default_warp_octaves")]
+    pub octaves: u8,
+    // terrainGeneration.noise when missing
+    pub noise: Option<InputNoiseType>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InputTerrainGeneration {
@@ -38,6 +116,20 @@ pub struct InputTerrainGeneration {
     pub fbm_iteration_scale_coefficient: f64,
     pub fbm_iteration_weight_coefficient: f64,
     pub fbm_final_power: f64,
+    #[serde(default)]
+    pub fbm_fractal: InputFractalType,
+    // ridge height of ridged and hybrid, gain is how much a ridge lets the next octave through
+    #[serde(default = "default_fractal_offset")]
+    pub fbm_offset: f64,
+    #[serde(default = "default_fractal_gain")]
+    pub fbm_gain: f64,
+    #[serde(default)]
+    pub noise: InputNoiseType,
+    #[serde(default)]
+    pub height_warp: Vec<InputDomainWarp>,
+    // applies to the random biome modifier and the biome randomizers
+    #[serde(default)]
+    pub biome_warp: Vec<InputDomainWarp>,
     pub height_modifiers: Vec<InputHeightModifier>,
     pub color_modifiers: Vec<InputColorModifier>,
     pub craters_count: u32,
@@ -54,7 +146,8 @@ pub enum InputBiomeModifier {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InputBiome {
-    pub id: u32,
+    // written as is into the 8 bit biome id maps and vertices, so bigger ids fail the parsing
+    pub id: u8,
     pub seed: f64,
     pub min_altitude: f64,
     pub max_altitude: f64,
@@ -67,6 +160,102 @@ pub struct InputBiome {
     pub craters_probability: f64,
     pub min_crater_size: f64,
     pub max_crater_size: f64,
+    // basis of the fbm that breaks up the biome borders
+    #[serde(default)]
+    pub randomizer_noise: InputNoiseType,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputNoiseBlend {
+    #[default]
+    Add,
+    Multiply,
+    Max,
+    Min,
+}
+
+// Fades the layer in where the noise of an earlier layer (0 to 1, before amplitude and offset)
+// goes from `from` to `to`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputNoiseMask {
+    pub layer: String,
+    pub from: f64,
+    pub to: f64,
+}
+
+fn default_octave_scale_coefficient() -> f64 {
+    2.0
+}
+
+fn default_octave_weight_coefficient() -> f64 {
+    0.5
+}
+
+fn default_power() -> f64 {
+    1.0
+}
+
+// Height in meters is offset + amplitude * fbm^power, combined with the layers before it by blend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputNoiseLayer {
+    pub name: String,
+    pub seed: f64,
+    pub scale: f64,
+    pub octaves: u8,
+    // terrainGeneration.noise when missing
+    pub noise: Option<InputNoiseType>,
+    #[serde(default = "default_octave_scale_coefficient")]
+    pub octave_scale_coefficient: f64,
+    #[serde(default = "default_octave_weight_coefficient")]
+    pub octave_weight_coefficient: f64,
+    #[serde(default = "default_power")]
+    pub power: f64,
+    #[serde(default)]
+    pub fractal: InputFractalType,
+    #[serde(default = "default_fractal_offset")]
+    pub fractal_offset: f64,
+    #[serde(default = "default_fractal_gain")]
+    pub fractal_gain: f64,
+    pub amplitude: f64,
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default)]
+    pub blend: InputNoiseBlend,
+    pub mask: Option<InputNoiseMask>,
+}
+
+// One step of the terrain pipeline, parameters left out fall back to terrainGeneration and
+// generatorConfig
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum InputTerrainStage {
+    Height,
+    NoiseLayers {
+        layers: Vec<InputNoiseLayer>,
+    },
+    HeightModifiers,
+    Biomes,
+    ColorModifiers,
+    Craters {
+        count: Option<u32>,
+    },
+    Erosion {
+        iterations: Option<u16>,
+        droplets_count: Option<u16>,
+        droplet_velocity_coefficient: Option<f64>,
+        droplet_evaporation_coefficient: Option<f64>,
+    },
+    Smoothing {
+        iterations: u32,
+        strength: f64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,6 +267,9 @@ pub struct InputTerrain {
     pub biome_modifier: InputBiomeModifier,
     pub biomes: Vec<InputBiome>,
     pub terrain_generation: InputTerrainGeneration,
+    // the default pipeline is used when missing
+    #[serde(default)]
+    pub stages: Option<Vec<InputTerrainStage>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -101,7 +293,24 @@ pub struct InputPlanetGenConfig {
     pub erosion_droplet_velocity_coefficient: f64,
     pub erosion_droplet_evaporation_coefficient: f64,
 
-    pub cube_map_resolution: u16,
+    pub cube_map_resolution: u32,
+
+    // Keeps the height, biome and water layers in memory mapped files in this directory, for
+    // resolutions that do not fit in memory
+    #[serde(default)]
+    pub storage_dir: Option<String>,
+
+    // Trades some speed for output that does not depend on the thread count
+    #[serde(default)]
+    pub deterministic: bool,
+
+    // Per biome weight maps for texture splatting, next to the biome color maps
+    #[serde(default)]
+    pub export_splat_maps: bool,
+
+    // Keeps the finished terrain as CompactTerrainLayers instead of the full layers
+    #[serde(default)]
+    pub compact_layers: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -113,7 +322,22 @@ pub struct InputCelestialBodyDefinition {
     pub generator_config: InputPlanetGenConfig,
 }
 
+// Also checks what serde cannot, so a broken definition fails here and not in the middle of a run
+pub fn try_parse_input_data(str: &str) -> Result<InputCelestialBodyDefinition, String> {
+    let data: InputCelestialBodyDefinition =
+        serde_json::from_str(str).map_err(|error| error.to_string())?;
+    let stages = data
+        .terrain
+        .iter()
+        .flat_map(|terrain| terrain.stages.iter().flatten());
+    for stage in stages {
+        if let InputTerrainStage::NoiseLayers { layers } = stage {
+            mask_layer_indices(layers)?;
+        }
+    }
+    Ok(data)
+}
+
 pub fn parse_input_data(str: &str) -> InputCelestialBodyDefinition {
-    let data: InputCelestialBodyDefinition = serde_json::from_str(str).unwrap();
-    data
+    try_parse_input_data(str).unwrap_or_else(|error| panic!("{error}"))
 }
@@ -0,0 +1,29 @@
+pub mod base_icosphere;
+pub mod compact_layers;
+pub mod container;
+pub mod craters;
+pub mod cubemap_data;
+pub mod cubemap_mips;
+pub mod cubemap_storage;
+pub mod domain_warp;
+pub mod erosion;
+pub mod generate_icosphere;
+pub mod generate_terrain;
+pub mod generate_water;
+pub mod json_input;
+pub mod math_util;
+pub mod modifiers;
+pub mod noise;
+pub mod noise_layers;
+pub mod planet_generator;
+pub mod planet_query;
+pub mod random;
+pub mod raycast;
+pub mod save_binary_maps;
+pub mod terrain_stages;
+
+pub use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
+pub use crate::generate_terrain::{InterpolatedBiomeData, TerrainLayers};
+pub use crate::json_input::InputCelestialBodyDefinition;
+pub use crate::planet_generator::{GeneratedPlanet, GeneratedTerrain, PlanetGenerator};
+pub use crate::planet_query::PlanetQuery;
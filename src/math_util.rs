@@ -1,3 +1,5 @@
+use glam::DVec3;
+
 pub fn mix(a: f64, b: f64, m: f64) -> f64 {
     a * (1.0 - m) + b * m
 }
@@ -9,3 +11,10 @@ pub fn map(x: f64, in_min: f64, in_max: f64, out_min: f64, out_max: f64) -> f64
 pub fn usat(a: f64) -> f64 {
     a.min(1.0).max(0.0)
 }
+
+// Latitude and longitude in degrees, +Y is the north pole and longitude 0 lies on +X
+pub fn lat_lon_to_direction(lat: f64, lon: f64) -> DVec3 {
+    let lat = lat.to_radians();
+    let lon = lon.to_radians();
+    DVec3::new(lat.cos() * lon.cos(), lat.sin(), lat.cos() * lon.sin())
+}
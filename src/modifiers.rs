@@ -0,0 +1,242 @@
+use crate::cubemap_data::{face_pixel_index, face_pixel_to_direction, CubeMapDataLayer};
+use crate::generate_terrain::InterpolatedBiomeData;
+use crate::json_input::{InputTerrain, InputVector3};
+use crate::math_util::mix;
+use glam::{DQuat, DVec2, DVec3, Vec3};
+use image::imageops::sample_bilinear;
+use image::{ImageResult, Rgba, Rgba32FImage};
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+
+// An image laid onto the sphere around a direction with a gnomonic projection, so it keeps its
+// shape around the center. Size is the angular width in degrees (below 180), rotation turns the
+// image clockwise around the direction, also in degrees
+pub struct ProjectedImage {
+    image: Rgba32FImage,
+    center: DVec3,
+    right: DVec3,
+    up: DVec3,
+    // tan of half of the angular size, the image covers -half_extent..half_extent on the plane
+    half_extent: f64,
+}
+
+impl ProjectedImage {
+    pub fn new(image: Rgba32FImage, direction: DVec3, size: f64, rotation: f64) -> ProjectedImage {
+        let center = direction.normalize();
+        // same tangent choice as the craters, +Y is the image top unless looking at the poles
+        let right = if center.y.abs() < 0.99 {
+            DVec3::new(0.0, 1.0, 0.0).cross(center).normalize()
+        } else {
+            DVec3::new(1.0, 0.0, 0.0).cross(center).normalize()
+        };
+        let up = center.cross(right);
+        let orient = DQuat::from_axis_angle(center, -rotation.to_radians());
+        ProjectedImage {
+            image,
+            center,
+            right: orient * right,
+            up: orient * up,
+            half_extent: (size.to_radians() * 0.5).tan(),
+        }
+    }
+
+    pub fn load(path: &str, direction: DVec3, size: f64, rotation: f64) -> ImageResult<Self> {
+        let image = image::open(path)?.to_rgba32f();
+        Ok(ProjectedImage::new(image, direction, size, rotation))
+    }
+
+    // Image uv in 0..1 with v going down, None if the direction is outside the image
+    pub fn project(&self, dir: DVec3) -> Option<DVec2> {
+        let forward = dir.dot(self.center);
+        if forward <= 0.0 {
+            return None;
+        }
+        let plane =
+            DVec2::new(dir.dot(self.right), dir.dot(self.up)) / (forward * self.half_extent);
+        let uv = DVec2::new(plane.x * 0.5 + 0.5, 0.5 - plane.y * 0.5);
+        if uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0 {
+            return None;
+        }
+        Some(uv)
+    }
+
+    // Bilinear RGBA in 0..1
+    pub fn sample(&self, dir: DVec3) -> Option<Rgba<f32>> {
+        let uv = self.project(dir)?;
+        sample_bilinear(&self.image, uv.x as f32, uv.y as f32)
+    }
+}
+
+pub fn vector3_to_dvec3(v: &InputVector3) -> DVec3 {
+    DVec3::new(v.x, v.y, v.z)
+}
+
+// Calls modify with the image sample for every pixel of the layer that the image covers
+fn modify_projected_pixels<Data: Clone + Send>(
+    layer: &mut CubeMapDataLayer<Data>,
+    image: &ProjectedImage,
+    modify: impl Fn(&mut Data, Rgba<f32>) + Sync,
+) {
+    let cube_map_res = layer.res;
+    let res = cube_map_res as usize;
+    layer
+        .faces_mut()
+        .into_par_iter()
+        .for_each(|(face, face_data)| {
+            for y in 0..res {
+                for x in 0..res {
+                    let dir = face_pixel_to_direction(cube_map_res, &face, x, y);
+                    if let Some(color) = image.sample(dir) {
+                        modify(&mut face_data[face_pixel_index(cube_map_res, x, y)], color);
+                    }
+                }
+            }
+        });
+}
+
+// Image brightness maps onto min_height..max_height (the same way the height PNGs are saved) and
+// replaces the generated height by influence times the image alpha
+pub fn apply_height_modifier(
+    cube_map_height: &mut CubeMapDataLayer<f64>,
+    image: &ProjectedImage,
+    min_height: f64,
+    max_height: f64,
+    influence: f64,
+) {
+    modify_projected_pixels(cube_map_height, image, |height, Rgba([r, g, b, a])| {
+        let value = (r + g + b) as f64 / 3.0;
+        *height = mix(
+            *height,
+            mix(min_height, max_height, value),
+            influence * a as f64,
+        );
+    });
+}
+
+// Replaces the biome color by influence times the image alpha, the image is expected in the same
+// 0..1 color space as the biome colors in the input
+pub fn apply_color_modifier(
+    cube_map_biome: &mut CubeMapDataLayer<InterpolatedBiomeData>,
+    image: &ProjectedImage,
+    influence: f64,
+) {
+    modify_projected_pixels(cube_map_biome, image, |biome, Rgba([r, g, b, a])| {
+        biome.color = biome
+            .color
+            .lerp(Vec3::new(r, g, b), (influence * a as f64) as f32);
+    });
+}
+
+pub fn apply_height_modifiers(terrain: &InputTerrain, cube_map_height: &mut CubeMapDataLayer<f64>) {
+    for modifier in &terrain.terrain_generation.height_modifiers {
+        println!("Applying height modifier {}", modifier.image_path);
+        let image = ProjectedImage::load(
+            &modifier.image_path,
+            vector3_to_dvec3(&modifier.direction),
+            modifier.size,
+            modifier.rotation,
+        )
+        .expect("Failed to load the height modifier image");
+        apply_height_modifier(
+            cube_map_height,
+            &image,
+            terrain.radius + terrain.min_height,
+            terrain.radius + terrain.max_height,
+            modifier.influence,
+        );
+    }
+}
+
+pub fn apply_color_modifiers(
+    terrain: &InputTerrain,
+    cube_map_biome: &mut CubeMapDataLayer<InterpolatedBiomeData>,
+) {
+    for modifier in &terrain.terrain_generation.color_modifiers {
+        println!("Applying color modifier {}", modifier.image_path);
+        let image = ProjectedImage::load(
+            &modifier.image_path,
+            vector3_to_dvec3(&modifier.direction),
+            modifier.size,
+            modifier.rotation,
+        )
+        .expect("Failed to load the color modifier image");
+        apply_color_modifier(cube_map_biome, &image, modifier.influence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_height_modifier_stamp() {
+        // white top half, black bottom half, fully opaque
+        let image = Rgba32FImage::from_fn(16, 16, |_, y| {
+            let value = if y < 8 { 1.0 } else { 0.0 };
+            Rgba([value, value, value, 1.0])
+        });
+        let image = ProjectedImage::new(image, DVec3::Z, 20.0, 0.0);
+
+        assert!(image.project(DVec3::Z).unwrap().distance(DVec2::splat(0.5)) < 1e-9);
+        assert!(image.project(-DVec3::Z).is_none());
+        assert!(image.project(DVec3::new(0.0, 0.3, 1.0)).is_none());
+        // +Y is the top of the image
+        assert!(image.project(DVec3::new(0.0, 0.1, 1.0)).unwrap().y < 0.5);
+
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(64, 1000.0);
+        apply_height_modifier(&mut height, &image, 900.0, 1100.0, 0.5);
+
+        let above = DVec3::new(0.0, 0.1, 1.0).normalize();
+        let below = DVec3::new(0.0, -0.1, 1.0).normalize();
+        assert!((height.get(above) - 1050.0).abs() < 1e-9);
+        assert!((height.get(below) - 950.0).abs() < 1e-9);
+        assert_eq!(height.get(DVec3::X), 1000.0);
+
+        // a quarter turn clockwise brings the white half to the right
+        let rotated = ProjectedImage::new(
+            Rgba32FImage::from_fn(16, 16, |_, y| Rgba([(y < 8) as u8 as f32; 4])),
+            DVec3::Z,
+            20.0,
+            90.0,
+        );
+        let right = DVec3::Y.cross(DVec3::Z);
+        assert_eq!(
+            rotated
+                .sample((DVec3::Z + right * 0.1).normalize())
+                .unwrap()[0],
+            1.0
+        );
+        assert_eq!(
+            rotated
+                .sample((DVec3::Z - right * 0.1).normalize())
+                .unwrap()[0],
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_color_modifier_blend() {
+        let biome = InterpolatedBiomeData {
+            dominating_id: 0,
+            second_id: 0,
+            second_weight: 0.0,
+            color: Vec3::new(0.0, 0.0, 1.0),
+            roughness: 0.5,
+            erosion_strength: 0.0,
+            deposition_strength: 0.0,
+            craters_probability: 0.0,
+            min_crater_size: 0.0,
+            max_crater_size: 0.0,
+        };
+        let mut layer = CubeMapDataLayer::new(32, biome);
+        // red with half transparency
+        let image = Rgba32FImage::from_pixel(4, 4, Rgba([1.0, 0.0, 0.0, 0.5]));
+        let image = ProjectedImage::new(image, DVec3::NEG_Y, 30.0, 45.0);
+        apply_color_modifier(&mut layer, &image, 1.0);
+
+        let stamped = layer.get(DVec3::NEG_Y);
+        assert!(stamped.color.distance(Vec3::new(0.5, 0.0, 0.5)) < 1e-6);
+        assert_eq!(stamped.roughness, 0.5);
+        assert_eq!(layer.get(DVec3::Y).color, Vec3::new(0.0, 0.0, 1.0));
+    }
+}
@@ -1,5 +1,9 @@
-use crate::math_util::map;
-use crate::random::random_3d_to_1d;
+use crate::json_input::{
+    InputDistanceMetric, InputFractalType, InputNoiseType, InputTerrainGeneration,
+    InputWorleyFeature,
+};
+use crate::math_util::{map, usat};
+use crate::random::random_3d_to_1d_seeded;
 use glam::DVec3;
 use rayon::prelude::*;
 use std::sync::atomic::{AtomicI64, Ordering};
@@ -17,7 +21,7 @@ static VEC6: DVec3 = DVec3::new(0.0, 1.0, 1.0);
 static VEC7: DVec3 = DVec3::new(1.0, 0.0, 1.0);
 static VEC8: DVec3 = DVec3::new(1.0, 1.0, 1.0);
 
-pub fn value_noise(x: DVec3) -> f64 {
+pub fn value_noise(x: DVec3, seed: u64) -> f64 {
     let p = x.floor();
     let mut fr = x - p;
     fr = fr * fr * (3.0 - 2.0 * fr);
@@ -31,15 +35,15 @@ pub fn value_noise(x: DVec3) -> f64 {
     let rbf = p + VEC7;
     let rtf = p + VEC8;
 
-    let l0candidate1 = random_3d_to_1d(lbz);
-    let l0candidate2 = random_3d_to_1d(rbz);
-    let l0candidate3 = random_3d_to_1d(ltz);
-    let l0candidate4 = random_3d_to_1d(rtz);
+    let l0candidate1 = random_3d_to_1d_seeded(lbz, seed);
+    let l0candidate2 = random_3d_to_1d_seeded(rbz, seed);
+    let l0candidate3 = random_3d_to_1d_seeded(ltz, seed);
+    let l0candidate4 = random_3d_to_1d_seeded(rtz, seed);
 
-    let l0candidate5 = random_3d_to_1d(lbf);
-    let l0candidate6 = random_3d_to_1d(rbf);
-    let l0candidate7 = random_3d_to_1d(ltf);
-    let l0candidate8 = random_3d_to_1d(rtf);
+    let l0candidate5 = random_3d_to_1d_seeded(lbf, seed);
+    let l0candidate6 = random_3d_to_1d_seeded(rbf, seed);
+    let l0candidate7 = random_3d_to_1d_seeded(ltf, seed);
+    let l0candidate8 = random_3d_to_1d_seeded(rtf, seed);
 
     let l1candidate1 = mix(l0candidate1, l0candidate2, fr.x);
     let l1candidate2 = mix(l0candidate3, l0candidate4, fr.x);
@@ -54,17 +58,331 @@ pub fn value_noise(x: DVec3) -> f64 {
     l3candidate1
 }
 
-pub fn super_value_noise(x: DVec3) -> f64 {
-    (value_noise(x) + value_noise(x + 4.5)) * 0.5
+pub fn super_value_noise(x: DVec3, seed: u64) -> f64 {
+    (value_noise(x, seed) + value_noise(x + 4.5, seed)) * 0.5
 }
 
-pub fn fbm(pos: DVec3, iterations: u8, scaler: f64, weighter: f64) -> f64 {
+// A 3D noise basis for fbm, values are in 0..1 like the value noise always was
+pub trait NoiseFunction: Send + Sync {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64;
+}
+
+pub struct ValueNoise;
+pub struct PerlinNoise;
+pub struct SimplexNoise;
+pub struct OpenSimplex2Noise;
+
+impl NoiseFunction for ValueNoise {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64 {
+        super_value_noise(pos, seed)
+    }
+}
+
+impl NoiseFunction for PerlinNoise {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64 {
+        usat(0.5 + 0.5 * perlin_noise(pos, seed))
+    }
+}
+
+impl NoiseFunction for SimplexNoise {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64 {
+        usat(0.5 + 0.5 * simplex_noise(pos, seed))
+    }
+}
+
+impl NoiseFunction for OpenSimplex2Noise {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64 {
+        usat(0.5 + 0.5 * open_simplex2_noise(pos, seed))
+    }
+}
+
+pub struct WorleyNoise {
+    pub feature: InputWorleyFeature,
+    pub metric: InputDistanceMetric,
+}
+
+impl NoiseFunction for WorleyNoise {
+    fn sample(&self, pos: DVec3, seed: u64) -> f64 {
+        let (f1, f2) = worley_noise(pos, seed, self.metric);
+        // the distances mostly stay below these, so the values use most of 0..1
+        let range = match self.metric {
+            InputDistanceMetric::Euclidean => 1.0,
+            InputDistanceMetric::Manhattan => 1.5,
+            InputDistanceMetric::Chebyshev => 0.8,
+        };
+        usat(match self.feature {
+            InputWorleyFeature::F1 => f1 / range,
+            InputWorleyFeature::F2 => f2 / (range * 1.1),
+            InputWorleyFeature::F2MinusF1 => (f2 - f1) / (range * 0.82),
+        })
+    }
+}
+
+pub fn noise_function(noise: InputNoiseType) -> Box<dyn NoiseFunction> {
+    match noise {
+        InputNoiseType::Value => Box::new(ValueNoise),
+        InputNoiseType::Perlin => Box::new(PerlinNoise),
+        InputNoiseType::Simplex => Box::new(SimplexNoise),
+        InputNoiseType::OpenSimplex2 => Box::new(OpenSimplex2Noise),
+        InputNoiseType::Worley { feature, metric } => Box::new(WorleyNoise { feature, metric }),
+    }
+}
+
+const PRIME_X: i64 = 0x5205402B9270C86F;
+const PRIME_Y: i64 = 0x598CD327003817B5;
+const PRIME_Z: i64 = 0x5BCC226E9FA0BACB;
+const HASH_MULTIPLIER: i64 = 0x53A3F72DEEC546F5;
+const SEED_FLIP_3D: i64 = -0x52D547B2E96ED629;
+
+// Middles of the cube edges, no gradient is axis aligned so there are no visible grid lines
+static GRADIENTS: [DVec3; 12] = [
+    DVec3::new(1.0, 1.0, 0.0),
+    DVec3::new(-1.0, 1.0, 0.0),
+    DVec3::new(1.0, -1.0, 0.0),
+    DVec3::new(-1.0, -1.0, 0.0),
+    DVec3::new(1.0, 0.0, 1.0),
+    DVec3::new(-1.0, 0.0, 1.0),
+    DVec3::new(1.0, 0.0, -1.0),
+    DVec3::new(-1.0, 0.0, -1.0),
+    DVec3::new(0.0, 1.0, 1.0),
+    DVec3::new(0.0, -1.0, 1.0),
+    DVec3::new(0.0, 1.0, -1.0),
+    DVec3::new(0.0, -1.0, -1.0),
+];
+
+// Lattice coordinates come already multiplied by the primes
+fn gradient(seed: i64, xp: i64, yp: i64, zp: i64) -> DVec3 {
+    let hash = (seed ^ xp ^ yp ^ zp).wrapping_mul(HASH_MULTIPLIER);
+    GRADIENTS[((hash ^ (hash >> 32)) as u64 % 12) as usize]
+}
+
+fn lattice_gradient(seed: i64, lattice: DVec3) -> DVec3 {
+    gradient(
+        seed,
+        (lattice.x as i64).wrapping_mul(PRIME_X),
+        (lattice.y as i64).wrapping_mul(PRIME_Y),
+        (lattice.z as i64).wrapping_mul(PRIME_Z),
+    )
+}
+
+// Improved Perlin noise in -1..1, zero on every lattice point
+pub fn perlin_noise(pos: DVec3, seed: u64) -> f64 {
+    let seed = seed as i64;
+    let p = pos.floor();
+    let f = pos - p;
+    let fade = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+
+    let corner = |offset: DVec3| lattice_gradient(seed, p + offset).dot(f - offset);
+
+    let x00 = mix(corner(VEC1), corner(VEC3), fade.x);
+    let x10 = mix(corner(VEC2), corner(VEC4), fade.x);
+    let x01 = mix(corner(VEC5), corner(VEC7), fade.x);
+    let x11 = mix(corner(VEC6), corner(VEC8), fade.x);
+    mix(mix(x00, x10, fade.y), mix(x01, x11, fade.y), fade.z) * PERLIN_SCALE
+}
+
+const PERLIN_SCALE: f64 = 0.9;
+
+// Classic simplex noise in -1..1, sums the 4 corners of the skewed tetrahedron around pos
+pub fn simplex_noise(pos: DVec3, seed: u64) -> f64 {
+    const SKEW: f64 = 1.0 / 3.0;
+    const UNSKEW: f64 = 1.0 / 6.0;
+    let seed = seed as i64;
+
+    let cell = (pos + (pos.x + pos.y + pos.z) * SKEW).floor();
+    let d0 = pos - (cell - (cell.x + cell.y + cell.z) * UNSKEW);
+
+    // which of the 6 tetrahedra of the cube, by the order of the offsets
+    let (step1, step2) = if d0.x >= d0.y {
+        if d0.y >= d0.z {
+            (VEC3, VEC4)
+        } else if d0.x >= d0.z {
+            (VEC3, VEC7)
+        } else {
+            (VEC5, VEC7)
+        }
+    } else if d0.y < d0.z {
+        (VEC5, VEC6)
+    } else if d0.x < d0.z {
+        (VEC2, VEC6)
+    } else {
+        (VEC2, VEC4)
+    };
+
+    [VEC1, step1, step2, VEC8]
+        .iter()
+        .enumerate()
+        .map(|(index, offset)| {
+            let d = d0 - *offset + index as f64 * UNSKEW;
+            // 0.5 and not the usual 0.6, the wider kernel reaches past the neighbouring
+            // tetrahedra and leaves small steps on the cell borders
+            let t = 0.5 - d.length_squared();
+            if t <= 0.0 {
+                0.0
+            } else {
+                t.powi(4) * lattice_gradient(seed, cell + *offset).dot(d)
+            }
+        })
+        .sum::<f64>()
+        * SIMPLEX_SCALE
+}
+
+const SIMPLEX_SCALE: f64 = 70.0;
+
+// OpenSimplex2 (the fast variant) in -1..1, evaluates the two interleaved cubic lattices of a
+// body centered cubic grid, rotated so that no axis lines up with the main diagonal
+pub fn open_simplex2_noise(pos: DVec3, seed: u64) -> f64 {
+    let mut seed = seed as i64;
+    let r = (2.0 / 3.0) * (pos.x + pos.y + pos.z);
+    let rotated = DVec3::splat(r) - pos;
+
+    let base = rotated.round();
+    let mut d = rotated - base;
+    // -1 when the point lies on the positive side of its closest vertex
+    let mut sign = DVec3::new(
+        if d.x >= 0.0 { -1.0 } else { 1.0 },
+        if d.y >= 0.0 { -1.0 } else { 1.0 },
+        if d.z >= 0.0 { -1.0 } else { 1.0 },
+    );
+    let mut a0 = d.abs();
+
+    let mut xp = (base.x as i64).wrapping_mul(PRIME_X);
+    let mut yp = (base.y as i64).wrapping_mul(PRIME_Y);
+    let mut zp = (base.z as i64).wrapping_mul(PRIME_Z);
+
+    let mut value = 0.0;
+    // with a radius squared of 0.5 only the closest vertex and one neighbour of each lattice can
+    // reach the point, anything wider would need more vertices to stay continuous
+    let mut a = 0.5 - d.length_squared();
+    for lattice in 0..2 {
+        if a > 0.0 {
+            value += a.powi(4) * gradient(seed, xp, yp, zp).dot(d);
+        }
+
+        // the second closest vertex of this lattice, along the axis closest to the point
+        if a0.x >= a0.y && a0.x >= a0.z {
+            let b = a + a0.x + a0.x;
+            if b > 1.0 {
+                let neighbour_xp = xp.wrapping_sub((sign.x as i64).wrapping_mul(PRIME_X));
+                let g = gradient(seed, neighbour_xp, yp, zp);
+                value += (b - 1.0).powi(4) * g.dot(d + DVec3::new(sign.x, 0.0, 0.0));
+            }
+        } else if a0.y > a0.x && a0.y >= a0.z {
+            let b = a + a0.y + a0.y;
+            if b > 1.0 {
+                let neighbour_yp = yp.wrapping_sub((sign.y as i64).wrapping_mul(PRIME_Y));
+                let g = gradient(seed, xp, neighbour_yp, zp);
+                value += (b - 1.0).powi(4) * g.dot(d + DVec3::new(0.0, sign.y, 0.0));
+            }
+        } else {
+            let b = a + a0.z + a0.z;
+            if b > 1.0 {
+                let neighbour_zp = zp.wrapping_sub((sign.z as i64).wrapping_mul(PRIME_Z));
+                let g = gradient(seed, xp, yp, neighbour_zp);
+                value += (b - 1.0).powi(4) * g.dot(d + DVec3::new(0.0, 0.0, sign.z));
+            }
+        }
+
+        if lattice == 1 {
+            break;
+        }
+
+        // move over to the other lattice, offset by half a cell
+        a0 = 0.5 - a0;
+        d = sign * a0;
+        a += 0.75 - a0.x - a0.y - a0.z;
+        if sign.x < 0.0 {
+            xp = xp.wrapping_add(PRIME_X);
+        }
+        if sign.y < 0.0 {
+            yp = yp.wrapping_add(PRIME_Y);
+        }
+        if sign.z < 0.0 {
+            zp = zp.wrapping_add(PRIME_Z);
+        }
+        sign = -sign;
+        seed ^= SEED_FLIP_3D;
+    }
+    value * OPEN_SIMPLEX2_SCALE
+}
+
+const OPEN_SIMPLEX2_SCALE: f64 = 70.0;
+
+fn distance(d: DVec3, metric: InputDistanceMetric) -> f64 {
+    match metric {
+        InputDistanceMetric::Euclidean => d.length(),
+        InputDistanceMetric::Manhattan => d.x.abs() + d.y.abs() + d.z.abs(),
+        InputDistanceMetric::Chebyshev => d.abs().max_element(),
+    }
+}
+
+// Point of a worley cell, anywhere inside of the cell
+fn cell_point(seed: i64, cell: DVec3) -> DVec3 {
+    let hash = (seed
+        ^ (cell.x as i64).wrapping_mul(PRIME_X)
+        ^ (cell.y as i64).wrapping_mul(PRIME_Y)
+        ^ (cell.z as i64).wrapping_mul(PRIME_Z))
+    .wrapping_mul(HASH_MULTIPLIER) as u64;
+    let hash = hash ^ (hash >> 29);
+    let unit = |bits: u64| (bits & 0x1FFFFF) as f64 / 0x1FFFFF as f64;
+    cell + DVec3::new(unit(hash), unit(hash >> 21), unit(hash >> 42))
+}
+
+// Distances to the closest and the second closest cell point. Rings of cells around pos are
+// searched until no cell of the next ring can be closer than f2: every metric is at least the
+// biggest axis distance, so a cell r rings out is at least r - 1 away. Cells of a ring that are
+// too far away as a whole are skipped
+pub fn worley_noise(pos: DVec3, seed: u64, metric: InputDistanceMetric) -> (f64, f64) {
+    let seed = seed as i64;
+    let cell = pos.floor();
+    let mut f1 = f64::MAX;
+    let mut f2 = f64::MAX;
+    let mut ring: i32 = 0;
+    while f2 > (ring - 1) as f64 {
+        for z in -ring..=ring {
+            for y in -ring..=ring {
+                for x in -ring..=ring {
+                    if x.abs().max(y.abs()).max(z.abs()) != ring {
+                        continue;
+                    }
+                    let corner = cell + DVec3::new(x as f64, y as f64, z as f64);
+                    let gap = (corner - pos).max(pos - corner - 1.0).max(DVec3::ZERO);
+                    if distance(gap, metric) >= f2 {
+                        continue;
+                    }
+                    let d = distance(cell_point(seed, corner) - pos, metric);
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        ring += 1;
+    }
+    (f1, f2)
+}
+
+pub fn fbm(pos: DVec3, seed: u64, iterations: u8, scaler: f64, weighter: f64) -> f64 {
+    fbm_with(&ValueNoise, pos, seed, iterations, scaler, weighter)
+}
+
+pub fn fbm_with<Noise: NoiseFunction + ?Sized>(
+    noise: &Noise,
+    pos: DVec3,
+    seed: u64,
+    iterations: u8,
+    scaler: f64,
+    weighter: f64,
+) -> f64 {
     let mut res = 0.0;
     let mut w = 1.0;
     let mut ws = 0.0;
     let mut s = 1.0;
     for i in 0..iterations {
-        res += super_value_noise((pos + 2.0) * s) * w;
+        res += noise.sample((pos + 2.0) * s, seed) * w;
         ws += w;
         s *= scaler;
         w *= weighter;
@@ -80,3 +398,312 @@ pub fn fbm(pos: DVec3, iterations: u8, scaler: f64, weighter: f64) -> f64 {
     // });
     // atomic.into_inner() as f64 / (i32::MAX) as f64 / iterations as f64
 }
+
+// Octave settings of a fractal, lacunarity scales the frequency and octave_weight the amplitude
+// from one octave to the next like the fbm scaler and weighter do
+pub struct Fractal {
+    pub fractal_type: InputFractalType,
+    pub octaves: u8,
+    pub lacunarity: f64,
+    pub octave_weight: f64,
+    pub offset: f64,
+    pub gain: f64,
+}
+
+impl Fractal {
+    pub fn from_terrain_generation(generation: &InputTerrainGeneration) -> Fractal {
+        Fractal {
+            fractal_type: generation.fbm_fractal,
+            octaves: generation.fbm_iterations,
+            lacunarity: generation.fbm_iteration_scale_coefficient,
+            octave_weight: generation.fbm_iteration_weight_coefficient,
+            offset: generation.fbm_offset,
+            gain: generation.fbm_gain,
+        }
+    }
+
+    // 0 to 1 like fbm
+    pub fn sample<Noise: NoiseFunction + ?Sized>(
+        &self,
+        noise: &Noise,
+        pos: DVec3,
+        seed: u64,
+    ) -> f64 {
+        match self.fractal_type {
+            InputFractalType::Fbm => fbm_with(
+                noise,
+                pos,
+                seed,
+                self.octaves,
+                self.lacunarity,
+                self.octave_weight,
+            ),
+            InputFractalType::Ridged => self.ridged(noise, pos, seed),
+            InputFractalType::Hybrid => self.hybrid(noise, pos, seed),
+            InputFractalType::Billow => self.billow(noise, pos, seed),
+        }
+    }
+
+    // -1 to 1 with the same range fix as fbm, most of the noise is within 0.25 to 0.75
+    fn signed<Noise: NoiseFunction + ?Sized>(noise: &Noise, pos: DVec3, seed: u64) -> f64 {
+        map(noise.sample(pos, seed), 0.25, 0.75, -1.0, 1.0).clamp(-1.0, 1.0)
+    }
+
+    // Sharp crests where the noise crosses zero, every ridge lets the next octave through by gain
+    // so the detail piles up on the crests and the valleys stay smooth
+    fn ridged<Noise: NoiseFunction + ?Sized>(&self, noise: &Noise, pos: DVec3, seed: u64) -> f64 {
+        let mut res = 0.0;
+        let mut w = 1.0;
+        let mut ws = 0.0;
+        let mut s = 1.0;
+        let mut feedback = 1.0;
+        for _ in 0..self.octaves {
+            let ridge = self.offset - Self::signed(noise, (pos + 2.0) * s, seed).abs();
+            let signal = ridge * ridge * feedback;
+            feedback = usat(signal * self.gain);
+            res += signal * w;
+            ws += w;
+            s *= self.lacunarity;
+            w *= self.octave_weight;
+        }
+        usat(res / (ws * self.offset * self.offset))
+    }
+
+    // Every octave is scaled by the ones before it, smooth lowlands and rough highlands
+    fn hybrid<Noise: NoiseFunction + ?Sized>(&self, noise: &Noise, pos: DVec3, seed: u64) -> f64 {
+        let mut res = 0.0;
+        let mut w = 1.0;
+        let mut ws = 0.0;
+        let mut s = 1.0;
+        let mut feedback = 1.0;
+        for _ in 0..self.octaves {
+            let signal = Self::signed(noise, (pos + 2.0) * s, seed) + self.offset;
+            res += signal * feedback * w;
+            ws += w;
+            feedback = usat(feedback * signal);
+            s *= self.lacunarity;
+            w *= self.octave_weight;
+        }
+        usat(res / (ws * (1.0 + self.offset)))
+    }
+
+    // Rounded hills from the absolute value of the noise
+    fn billow<Noise: NoiseFunction + ?Sized>(&self, noise: &Noise, pos: DVec3, seed: u64) -> f64 {
+        let mut res = 0.0;
+        let mut w = 1.0;
+        let mut ws = 0.0;
+        let mut s = 1.0;
+        for _ in 0..self.octaves {
+            res += Self::signed(noise, (pos + 2.0) * s, seed).abs() * w;
+            ws += w;
+            s *= self.lacunarity;
+            w *= self.octave_weight;
+        }
+        usat(res / ws)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{derive_seed, master_seed, SEED_STREAM_HEIGHT};
+
+    const GRADIENT_NOISES: [(&str, fn(DVec3, u64) -> f64); 3] = [
+        ("perlin", perlin_noise),
+        ("simplex", simplex_noise),
+        ("open simplex 2", open_simplex2_noise),
+    ];
+
+    #[test]
+    fn test_gradient_noise_range() {
+        for (name, noise) in GRADIENT_NOISES {
+            let (mut min, mut max) = (f64::MAX, f64::MIN);
+            for i in 0..1_000_000u64 {
+                let pos = DVec3::new(
+                    (i % 100) as f64 * 0.173,
+                    ((i / 100) % 100) as f64 * 0.191,
+                    ((i / 10_000) % 100) as f64 * 0.207,
+                ) - 9.0;
+                let value = noise(pos, i / 250_000);
+                min = min.min(value);
+                max = max.max(value);
+            }
+            assert!(min >= -1.0 && max <= 1.0, "{name} {min} {max}");
+            // and it is not squashed into a small part of the range
+            assert!(min < -0.6 && max > 0.6, "{name} {min} {max}");
+        }
+
+        for noise in [
+            noise_function(InputNoiseType::Perlin),
+            noise_function(InputNoiseType::Simplex),
+            noise_function(InputNoiseType::OpenSimplex2),
+        ] {
+            for i in 0..1000 {
+                let value = noise.sample(DVec3::new(i as f64 * 0.31, 1.7, -2.3), 5);
+                assert!((0.0..=1.0).contains(&value));
+            }
+        }
+        // gradient noise has no value on the lattice, only slopes
+        assert_eq!(perlin_noise(DVec3::new(3.0, -2.0, 7.0), 1), 0.0);
+    }
+
+    #[test]
+    fn test_gradient_noise_continuity() {
+        const STEP: f64 = 1e-5;
+        for (name, noise) in GRADIENT_NOISES {
+            // a skewed line crossing many cells of both the cubic and the simplex lattices
+            let direction = DVec3::new(1.0, 0.37, -0.61).normalize();
+            let mut previous = noise(DVec3::ZERO, 3);
+            for i in 1..300_000 {
+                let value = noise(direction * (i as f64 * STEP), 3);
+                assert!(
+                    (value - previous).abs() < 20.0 * STEP,
+                    "{name} jumps at {} by {}",
+                    i as f64 * STEP,
+                    value - previous
+                );
+                previous = value;
+            }
+            let pos = DVec3::new(0.3, 0.7, 0.2);
+            assert_ne!(noise(pos, 3), noise(pos, 4));
+        }
+    }
+
+    #[test]
+    fn test_worley_noise() {
+        let noise: InputNoiseType = serde_json::from_str(
+            r#"{ "worley": { "feature": "f2MinusF1", "metric": "manhattan" } }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            noise,
+            InputNoiseType::Worley {
+                feature: InputWorleyFeature::F2MinusF1,
+                metric: InputDistanceMetric::Manhattan
+            }
+        ));
+
+        // the distance to a cell point is zero right on it
+        let point = cell_point(11, DVec3::new(2.0, -3.0, 5.0));
+        assert_eq!(
+            worley_noise(point, 11, InputDistanceMetric::Euclidean).0,
+            0.0
+        );
+
+        for i in 0..10_000 {
+            let pos = DVec3::new(i as f64 * 0.037, (i as f64 * 0.013).sin() * 4.0, -1.3);
+            let (f1, f2) = worley_noise(pos, 11, InputDistanceMetric::Euclidean);
+            assert!(f1 <= f2);
+            // manhattan is never shorter and chebyshev never longer than the straight line
+            assert!(worley_noise(pos, 11, InputDistanceMetric::Manhattan).0 >= f1 - 1e-12);
+            assert!(worley_noise(pos, 11, InputDistanceMetric::Chebyshev).0 <= f1 + 1e-12);
+            // F1 is continuous, the steepest it can change is 1 per unit
+            let next = worley_noise(pos + DVec3::X * 1e-4, 11, InputDistanceMetric::Euclidean);
+            assert!((next.0 - f1).abs() <= 1e-4 + 1e-12);
+
+            for feature in [
+                InputWorleyFeature::F1,
+                InputWorleyFeature::F2,
+                InputWorleyFeature::F2MinusF1,
+            ] {
+                let value = noise_function(InputNoiseType::Worley {
+                    feature,
+                    metric: InputDistanceMetric::Chebyshev,
+                })
+                .sample(pos, 11);
+                assert!((0.0..=1.0).contains(&value));
+            }
+        }
+    }
+
+    #[test]
+    fn test_worley_matches_brute_force() {
+        // no point of a cell more than 4 cells away can be among the two closest
+        let brute_force = |pos: DVec3, metric| {
+            let mut distances = vec![];
+            for z in -4..=4 {
+                for y in -4..=4 {
+                    for x in -4..=4 {
+                        let corner = pos.floor() + DVec3::new(x as f64, y as f64, z as f64);
+                        distances.push(distance(cell_point(7, corner) - pos, metric));
+                    }
+                }
+            }
+            distances.sort_by(f64::total_cmp);
+            (distances[0], distances[1])
+        };
+        for metric in [
+            InputDistanceMetric::Euclidean,
+            InputDistanceMetric::Manhattan,
+            InputDistanceMetric::Chebyshev,
+        ] {
+            for i in 0..300 {
+                let pos = DVec3::new(
+                    i as f64 * 0.37,
+                    (i as f64 * 1.3).sin() * 9.0,
+                    i as f64 * -0.11,
+                );
+                assert_eq!(
+                    worley_noise(pos, 7, metric),
+                    brute_force(pos, metric),
+                    "{pos}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fbm_seeding() {
+        let seed_a = derive_seed(master_seed(123324.0), SEED_STREAM_HEIGHT);
+        let seed_b = derive_seed(master_seed(123325.0), SEED_STREAM_HEIGHT);
+        assert_ne!(seed_a, seed_b);
+
+        for i in 0..64 {
+            let pos = DVec3::new(i as f64 * 0.37, i as f64 * -0.11, 1.5);
+            let a = fbm(pos, seed_a, 8, 2.0, 0.5);
+            assert_eq!(a, fbm(pos, seed_a, 8, 2.0, 0.5));
+            assert_ne!(a, fbm(pos, seed_b, 8, 2.0, 0.5));
+        }
+    }
+
+    #[test]
+    fn test_fractal_range() {
+        let seed = derive_seed(master_seed(123324.0), SEED_STREAM_HEIGHT);
+        for noise in [InputNoiseType::Value, InputNoiseType::Simplex] {
+            let noise = noise_function(noise);
+            for fractal_type in [
+                InputFractalType::Fbm,
+                InputFractalType::Ridged,
+                InputFractalType::Hybrid,
+                InputFractalType::Billow,
+            ] {
+                let fractal = Fractal {
+                    fractal_type,
+                    octaves: 6,
+                    lacunarity: 2.0,
+                    octave_weight: 0.5,
+                    offset: 1.0,
+                    gain: 2.0,
+                };
+                let (mut min, mut max) = (f64::MAX, f64::MIN);
+                for i in 0..20000 {
+                    let pos = DVec3::new(i as f64 * 0.0137, (i as f64 * 0.71).sin(), 0.3);
+                    let value = fractal.sample(noise.as_ref(), pos, seed);
+                    assert!((0.0..=1.0).contains(&value), "{:?} {}", fractal_type, value);
+                    if fractal_type == InputFractalType::Fbm {
+                        assert_eq!(value, fbm_with(noise.as_ref(), pos, seed, 6, 2.0, 0.5));
+                    }
+                    min = min.min(value);
+                    max = max.max(value);
+                }
+                assert!(
+                    max - min > 0.4,
+                    "{:?} spans {} to {}",
+                    fractal_type,
+                    min,
+                    max
+                );
+            }
+        }
+    }
+}
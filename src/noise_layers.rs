@@ -0,0 +1,225 @@
+use crate::cubemap_data::CubeMapDataLayer;
+use crate::domain_warp::DomainWarp;
+use crate::json_input::{InputNoiseBlend, InputNoiseLayer, InputTerrain};
+use crate::math_util::{map, mix, usat};
+use crate::noise::{noise_function, Fractal, NoiseFunction};
+use crate::random::{derive_seed, master_seed, SEED_STREAM_HEIGHT_WARP, SEED_STREAM_NOISE_LAYERS};
+use glam::DVec3;
+
+struct NoiseMask {
+    layer: usize,
+    from: f64,
+    to: f64,
+}
+
+struct NoiseLayer {
+    noise: Box<dyn NoiseFunction>,
+    seed: u64,
+    scale: f64,
+    fractal: Fractal,
+    power: f64,
+    amplitude: f64,
+    offset: f64,
+    blend: InputNoiseBlend,
+    mask: Option<NoiseMask>,
+}
+
+// Noise layers with the masks resolved to layer indices
+pub struct NoiseLayers {
+    layers: Vec<NoiseLayer>,
+    // terrainGeneration.heightWarp, shared by all the layers
+    warp: DomainWarp,
+}
+
+// Index of the layer every mask names, an error if it is missing or comes later
+pub fn mask_layer_indices(layers: &[InputNoiseLayer]) -> Result<Vec<Option<usize>>, String> {
+    layers
+        .iter()
+        .enumerate()
+        .map(|(index, layer)| {
+            layer
+                .mask
+                .as_ref()
+                .map(|mask| {
+                    layers[..index]
+                        .iter()
+                        .position(|other| other.name == mask.layer)
+                        .ok_or_else(|| {
+                            format!(
+                                "Noise layer {} is masked by {} which is not an earlier layer",
+                                layer.name, mask.layer
+                            )
+                        })
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+impl NoiseLayers {
+    // The masks are checked when the input is parsed and the stages are built, see
+    // mask_layer_indices
+    pub fn new(terrain: &InputTerrain, layers: &[InputNoiseLayer]) -> NoiseLayers {
+        let mask_layers = mask_layer_indices(layers).unwrap_or_else(|error| panic!("{error}"));
+        let stream_seed = derive_seed(
+            master_seed(terrain.terrain_generation.seed),
+            SEED_STREAM_NOISE_LAYERS,
+        );
+        NoiseLayers {
+            layers: layers
+                .iter()
+                .enumerate()
+                .map(|(index, layer)| NoiseLayer {
+                    noise: noise_function(layer.noise.unwrap_or(terrain.terrain_generation.noise)),
+                    seed: derive_seed(derive_seed(stream_seed, index as u64), layer.seed.to_bits()),
+                    scale: layer.scale,
+                    fractal: Fractal {
+                        fractal_type: layer.fractal,
+                        octaves: layer.octaves,
+                        lacunarity: layer.octave_scale_coefficient,
+                        octave_weight: layer.octave_weight_coefficient,
+                        offset: layer.fractal_offset,
+                        gain: layer.fractal_gain,
+                    },
+                    power: layer.power,
+                    amplitude: layer.amplitude,
+                    offset: layer.offset,
+                    blend: layer.blend,
+                    mask: layer.mask.as_ref().map(|mask| NoiseMask {
+                        layer: mask_layers[index].unwrap(),
+                        from: mask.from,
+                        to: mask.to,
+                    }),
+                })
+                .collect(),
+            warp: DomainWarp::new(
+                &terrain.terrain_generation.height_warp,
+                terrain.terrain_generation.noise,
+                derive_seed(
+                    master_seed(terrain.terrain_generation.seed),
+                    SEED_STREAM_HEIGHT_WARP,
+                ),
+            ),
+        }
+    }
+
+    // Height relative to the radius, noise keeps the 0 to 1 value of every layer for the masks
+    pub fn height(&self, dir: DVec3, noise: &mut Vec<f64>) -> f64 {
+        noise.clear();
+        let dir = self.warp.warp(dir);
+        let mut height = 0.0;
+        for layer in &self.layers {
+            let unorm = if layer.fractal.octaves == 0 {
+                0.0
+            } else {
+                layer
+                    .fractal
+                    .sample(layer.noise.as_ref(), dir * layer.scale, layer.seed)
+                    .powf(layer.power)
+            };
+            noise.push(unorm);
+
+            let value = layer.offset + layer.amplitude * unorm;
+            let blended = match layer.blend {
+                InputNoiseBlend::Add => height + value,
+                InputNoiseBlend::Multiply => height * value,
+                InputNoiseBlend::Max => f64::max(height, value),
+                InputNoiseBlend::Min => f64::min(height, value),
+            };
+            height = match &layer.mask {
+                Some(mask) => mix(
+                    height,
+                    blended,
+                    usat(map(noise[mask.layer], mask.from, mask.to, 0.0, 1.0)),
+                ),
+                None => blended,
+            };
+        }
+        height
+    }
+}
+
+pub fn generate_noise_layers(
+    terrain: &InputTerrain,
+    layers: &NoiseLayers,
+    cube_map_height: &mut CubeMapDataLayer<f64>,
+) {
+    println!("Generating noise layers, res: {}", cube_map_height.res);
+    cube_map_height.par_map_pixels_init(
+        || Vec::with_capacity(layers.layers.len()),
+        |noise, _, _, _, dir| terrain.radius + layers.height(dir, noise),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_input::{parse_input_data, try_parse_input_data, InputTerrainStage};
+    use crate::terrain_stages::build_terrain_stages;
+
+    #[test]
+    fn test_blend_modes_and_masks() {
+        let input = parse_input_data(include_str!("../test-input.json"));
+        let terrain = input.terrain.as_ref().unwrap();
+        let layers: Vec<InputNoiseLayer> = serde_json::from_str(
+            r#"[
+                { "name": "base", "seed": 1, "scale": 1, "octaves": 0, "amplitude": 0, "offset": 100 },
+                { "name": "double", "seed": 2, "scale": 1, "octaves": 0, "amplitude": 0, "offset": 3, "blend": "multiply" },
+                { "name": "cap", "seed": 3, "scale": 1, "octaves": 0, "amplitude": 0, "offset": 250, "blend": "min" },
+                { "name": "floor", "seed": 4, "scale": 1, "octaves": 0, "amplitude": 0, "offset": 200, "blend": "max" }
+            ]"#,
+        )
+        .unwrap();
+        let mut noise = vec![];
+        let height = NoiseLayers::new(terrain, &layers).height(DVec3::X, &mut noise);
+        assert_eq!(height, 250.0);
+
+        // continents are in the upper half of the noise, mountains only grow on them
+        let layers: Vec<InputNoiseLayer> = serde_json::from_str(
+            r#"[
+                { "name": "continents", "seed": 1, "scale": 2, "octaves": 4, "amplitude": 0 },
+                { "name": "mountains", "seed": 2, "scale": 8, "octaves": 4, "amplitude": 1000,
+                  "mask": { "layer": "continents", "from": 0.5, "to": 0.501 } }
+            ]"#,
+        )
+        .unwrap();
+        let layers = NoiseLayers::new(terrain, &layers);
+        let (mut flat, mut mountains) = (0, 0);
+        for i in 0..256 {
+            let dir = DVec3::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos(), 0.5).normalize();
+            let height = layers.height(dir, &mut noise);
+            if noise[0] <= 0.5 {
+                assert_eq!(height, 0.0);
+                flat += 1;
+            } else if noise[0] >= 0.501 {
+                assert_eq!(height, 1000.0 * noise[1]);
+                mountains += 1;
+            }
+        }
+        assert!(flat > 0 && mountains > 0);
+    }
+
+    #[test]
+    fn test_mask_has_to_name_an_earlier_layer() {
+        let layers_json = r#"[
+            { "name": "a", "seed": 1, "scale": 1, "octaves": 1, "amplitude": 1,
+              "mask": { "layer": "b", "from": 0, "to": 1 } },
+            { "name": "b", "seed": 1, "scale": 1, "octaves": 1, "amplitude": 1 }
+        ]"#;
+        let layers: Vec<InputNoiseLayer> = serde_json::from_str(layers_json).unwrap();
+        let error = mask_layer_indices(&layers).err().unwrap();
+        assert_eq!(
+            error,
+            "Noise layer a is masked by b which is not an earlier layer"
+        );
+        assert!(build_terrain_stages(&[InputTerrainStage::NoiseLayers { layers }]).is_err());
+
+        // the whole definition already fails parsing
+        let json = include_str!("../test-input.json").replacen(
+            r#""terrainGeneration": {"#,
+            &format!(r#""stages": [{{ "type": "noiseLayers", "layers": {layers_json} }}], "terrainGeneration": {{"#),
+            1,
+        );
+        assert_eq!(try_parse_input_data(&json).err().unwrap(), error);
+    }
+}
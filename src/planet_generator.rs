@@ -0,0 +1,281 @@
+use crate::compact_layers::CompactTerrainLayers;
+use crate::container::save_planet_container;
+use crate::cubemap_data::CubeMapDataLayer;
+use crate::generate_icosphere::{generate_icosphere, save_icosphere_raw, IcosphereChunk};
+use crate::generate_terrain::{
+    generate_terrain_layers, save_splat_maps, save_terrain_layers, TerrainLayers, TerrainMaps,
+};
+use crate::generate_water::generate_water_layer;
+use crate::json_input::{parse_input_data, InputCelestialBodyDefinition};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+// Terrain layers as they are kept once generated, compact with generatorConfig.compactLayers
+pub enum GeneratedTerrain {
+    Full(TerrainLayers),
+    Compact(CompactTerrainLayers),
+}
+
+impl GeneratedTerrain {
+    // Either form read as it is, without unpacking the compact one
+    pub fn maps(&self) -> &dyn TerrainMaps {
+        match self {
+            GeneratedTerrain::Full(layers) => layers,
+            GeneratedTerrain::Compact(compact) => compact,
+        }
+    }
+}
+
+// Everything the generator produces, kept in memory until (and if) it gets saved
+pub struct GeneratedPlanet {
+    pub terrain: Option<GeneratedTerrain>,
+    pub terrain_icosphere: Vec<IcosphereChunk>,
+    pub water: Option<CubeMapDataLayer<f64>>,
+    pub water_icosphere: Vec<IcosphereChunk>,
+}
+
+// Entry point for embedding the generator, the CLI is just a thin wrapper over this
+pub struct PlanetGenerator {
+    input: InputCelestialBodyDefinition,
+}
+
+fn recreate_dir(dir: &Path) {
+    match fs::remove_dir_all(dir) {
+        Ok(_) => {}
+        Err(e) => match e.kind() {
+            ErrorKind::NotFound => (), // this is fine
+            _ => panic!("Failed to delete the directory because {}", e),
+        },
+    }
+
+    fs::create_dir_all(dir.join("icosphere")).expect("Failed to create the output directory");
+}
+
+impl PlanetGenerator {
+    pub fn new(input: InputCelestialBodyDefinition) -> PlanetGenerator {
+        PlanetGenerator { input }
+    }
+
+    pub fn from_json(json: &str) -> PlanetGenerator {
+        PlanetGenerator::new(parse_input_data(json))
+    }
+
+    pub fn input(&self) -> &InputCelestialBodyDefinition {
+        &self.input
+    }
+
+    // None if the definition has no terrain
+    pub fn generate_terrain(&self) -> Option<TerrainLayers> {
+        let terrain = self.input.terrain.as_ref()?;
+        Some(generate_terrain_layers(&self.input, terrain))
+    }
+
+    // None if the definition has no water
+    pub fn generate_water(&self) -> Option<CubeMapDataLayer<f64>> {
+        let water = self.input.water.as_ref()?;
+        Some(generate_water_layer(&self.input, water))
+    }
+
+    pub fn generate_terrain_icosphere(&self, layers: &TerrainLayers) -> Vec<IcosphereChunk> {
+        let config = &self.input.generator_config;
+        println!("Generating terrain icosphere");
+        generate_icosphere(
+            &layers.height,
+            Some(&layers.biome),
+            self.input.terrain.as_ref().map_or(0.0, |t| t.radius),
+            config.subdivide_initial,
+            config.subdivide_level1,
+            config.subdivide_level2,
+            config.subdivide_level3,
+        )
+    }
+
+    pub fn generate_water_icosphere(&self, water: &CubeMapDataLayer<f64>) -> Vec<IcosphereChunk> {
+        let config = &self.input.generator_config;
+        println!("Generating water icosphere");
+        generate_icosphere(
+            water,
+            None,
+            self.input.water.as_ref().map_or(0.0, |w| w.height),
+            config.subdivide_initial,
+            config.subdivide_level1,
+            config.subdivide_level2,
+            config.subdivide_level3,
+        )
+    }
+
+    // Packs the layers when generatorConfig.compactLayers is set
+    pub fn keep_terrain(&self, layers: TerrainLayers) -> GeneratedTerrain {
+        match &self.input.terrain {
+            Some(terrain) if self.input.generator_config.compact_layers => {
+                println!("Packing terrain layers");
+                GeneratedTerrain::Compact(CompactTerrainLayers::from_layers(terrain, &layers))
+            }
+            _ => GeneratedTerrain::Full(layers),
+        }
+    }
+
+    pub fn generate(&self) -> GeneratedPlanet {
+        let water = self.generate_water();
+        let water_icosphere = water
+            .as_ref()
+            .map(|water| self.generate_water_icosphere(water))
+            .unwrap_or_default();
+
+        let terrain = self.generate_terrain();
+        let terrain_icosphere = terrain
+            .as_ref()
+            .map(|layers| self.generate_terrain_icosphere(layers))
+            .unwrap_or_default();
+        // the icosphere samples the full layers, they are only dropped after it
+        let terrain = terrain.map(|layers| self.keep_terrain(layers));
+
+        GeneratedPlanet {
+            terrain,
+            terrain_icosphere,
+            water,
+            water_icosphere,
+        }
+    }
+
+    // Saves into generatorConfig.outDir
+    pub fn save(&self, planet: &GeneratedPlanet) {
+        self.save_to(planet, Path::new(&self.input.generator_config.out_dir));
+    }
+
+    // Cleans the terrain and water directories under out_dir and writes everything there, plus
+    // the same data packed into a single planet.pgen container
+    pub fn save_to(&self, planet: &GeneratedPlanet, out_dir: &Path) {
+        let water_out_dir = out_dir.join("water");
+        recreate_dir(&water_out_dir);
+        if planet.water.is_some() {
+            println!("Saving water icosphere");
+            save_icosphere_raw(
+                water_out_dir.join("icosphere").to_str().unwrap(),
+                &planet.water_icosphere,
+            );
+        }
+
+        let terrain_out_dir = out_dir.join("terrain");
+        recreate_dir(&terrain_out_dir);
+        if let (Some(terrain), Some(generated)) = (&self.input.terrain, &planet.terrain) {
+            let maps = generated.maps();
+            save_terrain_layers(&terrain_out_dir, terrain, maps);
+            if self.input.generator_config.export_splat_maps {
+                save_splat_maps(&terrain_out_dir, maps.splat());
+            }
+            println!("Saving terrain icosphere");
+            save_icosphere_raw(
+                terrain_out_dir.join("icosphere").to_str().unwrap(),
+                &planet.terrain_icosphere,
+            );
+        }
+
+        println!("Saving planet container");
+        save_planet_container(&out_dir.join("planet.pgen"), &self.input, planet)
+            .expect("Failed to save the planet container");
+    }
+
+    // Same as running the CLI
+    pub fn generate_and_save(&self) {
+        self.save(&self.generate());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cubemap_data::CubeMapFace;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    fn hash_terrain(layers: &TerrainLayers) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let res = layers.height.res as usize;
+        for face in [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ] {
+            for y in 0..res {
+                for x in 0..res {
+                    layers
+                        .height
+                        .get_pixel(&face, x, y)
+                        .to_bits()
+                        .hash(&mut hasher);
+                    let biome = layers.biome.get_pixel(&face, x, y);
+                    biome.color.to_array().map(f32::to_bits).hash(&mut hasher);
+                    biome.roughness.to_bits().hash(&mut hasher);
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_deterministic_across_thread_counts() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 32;
+        input.generator_config.erosion_iterations = 3;
+        input.generator_config.erosion_droplets_count = 20;
+        input.generator_config.deterministic = true;
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.terrain_generation.craters_count = 4;
+        terrain.terrain_generation.fbm_iterations = 4;
+        let generator = PlanetGenerator::new(input);
+
+        let hashes: Vec<u64> = [1, 4]
+            .map(|threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap()
+                    .install(|| hash_terrain(&generator.generate_terrain().unwrap()))
+            })
+            .to_vec();
+        assert_eq!(hashes[0], hashes[1]);
+    }
+
+    #[test]
+    fn test_compact_layers_replace_the_full_layers() {
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 16;
+        input.generator_config.erosion_iterations = 1;
+        input.generator_config.erosion_droplets_count = 10;
+        input.generator_config.subdivide_initial = 0;
+        input.generator_config.subdivide_level1 = 1;
+        input.generator_config.subdivide_level2 = 1;
+        input.generator_config.subdivide_level3 = 1;
+        input.generator_config.compact_layers = true;
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.terrain_generation.craters_count = 2;
+        terrain.terrain_generation.fbm_iterations = 2;
+        let generator = PlanetGenerator::new(input);
+
+        let planet = generator.generate();
+        let Some(GeneratedTerrain::Compact(compact)) = &planet.terrain else {
+            panic!("Expected compact terrain layers");
+        };
+
+        // the container gets the same heights the full layers would give
+        let path =
+            std::env::temp_dir().join(format!("planetgen-compact-{}.pgen", std::process::id()));
+        save_planet_container(&path, generator.input(), &planet).unwrap();
+        let loaded = crate::container::PlanetContainer::open(&path)
+            .unwrap()
+            .read_height_layer()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        for (x, y) in [(0, 0), (7, 3), (15, 15)] {
+            assert_eq!(
+                loaded.get_pixel(&CubeMapFace::NZ, x, y),
+                compact.height.get_pixel(&CubeMapFace::NZ, x, y)
+            );
+        }
+    }
+}
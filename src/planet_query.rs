@@ -0,0 +1,262 @@
+use crate::container::PlanetContainer;
+use crate::cubemap_data::CubeMapDataLayer;
+use crate::cubemap_mips::CubeMapMips;
+use crate::generate_terrain::{InterpolatedBiomeData, TerrainLayers};
+use crate::math_util::lat_lon_to_direction;
+use crate::raycast::{raycast, HeightBounds, Hit};
+use glam::{DVec2, DVec3};
+use rayon::iter::ParallelIterator;
+use rayon::prelude::IntoParallelRefIterator;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// Lookups on a generated or loaded planet. Heights are distances from the planet center and are
+// sampled exactly the same way the icosphere meshes are built, so the values match the vertices:
+// height_at_dir for the levels dense enough to sample the full resolution layer, height_at_dir_lod
+// with the footprint of the level for the coarser ones.
+pub struct PlanetQuery {
+    height: CubeMapDataLayer<f64>,
+    biome: Option<CubeMapDataLayer<InterpolatedBiomeData>>,
+    // built on the first lod lookup
+    mip_levels: OnceLock<Vec<CubeMapDataLayer<f64>>>,
+    // built on the first raycast
+    bounds: OnceLock<HeightBounds>,
+}
+
+impl PlanetQuery {
+    pub fn new(
+        height: CubeMapDataLayer<f64>,
+        biome: Option<CubeMapDataLayer<InterpolatedBiomeData>>,
+    ) -> PlanetQuery {
+        PlanetQuery {
+            height,
+            biome,
+            mip_levels: OnceLock::new(),
+            bounds: OnceLock::new(),
+        }
+    }
+
+    pub fn from_terrain(layers: TerrainLayers) -> PlanetQuery {
+        PlanetQuery::new(layers.height, Some(layers.biome))
+    }
+
+    // Only heights are saved in a readable form, so a loaded planet has no biome data
+    pub fn load(input_dir: &str) -> std::io::Result<PlanetQuery> {
+        Ok(PlanetQuery::new(
+            CubeMapDataLayer::load_terrain_maps(input_dir)?,
+            None,
+        ))
+    }
+
+    pub fn load_container(path: &Path) -> std::io::Result<PlanetQuery> {
+        Ok(PlanetQuery::new(
+            PlanetContainer::open(path)?.read_height_layer()?,
+            None,
+        ))
+    }
+
+    pub fn height_layer(&self) -> &CubeMapDataLayer<f64> {
+        &self.height
+    }
+
+    pub fn biome_layer(&self) -> Option<&CubeMapDataLayer<InterpolatedBiomeData>> {
+        self.biome.as_ref()
+    }
+
+    pub fn height_at_dir(&self, dir: DVec3) -> f64 {
+        self.height.get_bilinear(dir.normalize())
+    }
+
+    // Filtered over footprint like the icosphere levels, get_level_footprint gives the footprint
+    // of a level from its subdivisions
+    pub fn height_at_dir_lod(&self, dir: DVec3, footprint: f64) -> f64 {
+        self.mips().get_trilinear(dir.normalize(), footprint)
+    }
+
+    fn mips(&self) -> CubeMapMips<'_> {
+        CubeMapMips::from_levels(
+            &self.height,
+            self.mip_levels
+                .get_or_init(|| self.height.build_mip_levels()),
+        )
+    }
+
+    // Latitude and longitude in degrees, see lat_lon_to_direction
+    pub fn height_at(&self, lat: f64, lon: f64) -> f64 {
+        self.height_at_dir(lat_lon_to_direction(lat, lon))
+    }
+
+    pub fn normal_at(&self, dir: DVec3) -> DVec3 {
+        let dir = dir.normalize();
+        self.height
+            .get_normal(dir, self.height.get_pixel_distance_for_dir(dir))
+    }
+
+    pub fn biome_at(&self, dir: DVec3) -> Option<InterpolatedBiomeData> {
+        self.biome.as_ref().map(|biome| biome.get(dir.normalize()))
+    }
+
+    // Closest terrain intersection within max_dist of the origin, positions are relative to the
+    // planet center like the heights
+    pub fn raycast(&self, origin: DVec3, dir: DVec3, max_dist: f64) -> Option<Hit> {
+        raycast(
+            &self.height,
+            self.biome.as_ref(),
+            self.bounds.get_or_init(|| HeightBounds::new(&self.height)),
+            origin,
+            dir,
+            max_dist,
+        )
+    }
+
+    pub fn raycasts(&self, rays: &[(DVec3, DVec3)], max_dist: f64) -> Vec<Option<Hit>> {
+        rays.par_iter()
+            .map(|(origin, dir)| self.raycast(*origin, *dir, max_dist))
+            .collect()
+    }
+
+    pub fn heights_at_dirs(&self, dirs: &[DVec3]) -> Vec<f64> {
+        dirs.par_iter()
+            .map(|dir| self.height_at_dir(*dir))
+            .collect()
+    }
+
+    // x is latitude and y is longitude
+    pub fn heights_at(&self, lat_lons: &[DVec2]) -> Vec<f64> {
+        lat_lons
+            .par_iter()
+            .map(|lat_lon| self.height_at(lat_lon.x, lat_lon.y))
+            .collect()
+    }
+
+    pub fn normals_at_dirs(&self, dirs: &[DVec3]) -> Vec<DVec3> {
+        dirs.par_iter().map(|dir| self.normal_at(*dir)).collect()
+    }
+
+    pub fn biomes_at_dirs(&self, dirs: &[DVec3]) -> Option<Vec<InterpolatedBiomeData>> {
+        let biome = self.biome.as_ref()?;
+        Some(
+            dirs.par_iter()
+                .map(|dir| biome.get(dir.normalize()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base_icosphere::get_base_icosphere;
+    use crate::cubemap_data::CubeMapFace;
+    use crate::generate_icosphere::{
+        generate_icosphere, get_level_footprint, subdivide_triangle_multiple, IcosphereChunk,
+    };
+
+    #[test]
+    fn test_height_at_matches_layer_sampling() {
+        const RES: u32 = 32;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 1000.0);
+        for y in 0..RES as usize {
+            for x in 0..RES as usize {
+                let dir = height.pixel_coords_to_direction(&CubeMapFace::PY, x, y);
+                height.set_pixel(&CubeMapFace::PY, x, y, 1000.0 + dir.x * 10.0);
+            }
+        }
+        let query = PlanetQuery::new(height, None);
+
+        let north_pole = lat_lon_to_direction(90.0, 0.0);
+        assert!(north_pole.distance(DVec3::new(0.0, 1.0, 0.0)) < 1e-12);
+
+        let lat_lons = [DVec2::new(60.0, 10.0), DVec2::new(75.0, -120.0)];
+        let batch = query.heights_at(&lat_lons);
+        lat_lons.iter().zip(batch).for_each(|(lat_lon, value)| {
+            let dir = lat_lon_to_direction(lat_lon.x, lat_lon.y);
+            let expected = query.height_layer().get_bilinear(dir.normalize());
+            assert_eq!(value.to_bits(), expected.to_bits());
+            assert_eq!(query.height_at(lat_lon.x, lat_lon.y), expected);
+        });
+        assert!(query.biome_at(north_pole).is_none());
+    }
+
+    // Every vertex of a level with the direction it was made from, in the order the mesh has them
+    fn level_vertices(
+        chunk: &IcosphereChunk,
+        subdivide_initial: u8,
+        subdivide_level: u8,
+        data: &[u8],
+    ) -> Vec<(DVec3, [f32; 3])> {
+        let base = get_base_icosphere()[chunk.index_main];
+        let triangle = subdivide_triangle_multiple(base, subdivide_initial)[chunk.index];
+        let dirs = subdivide_triangle_multiple(triangle, subdivide_level)
+            .into_iter()
+            .flat_map(|t| t.map(DVec3::normalize));
+        let positions = data.chunks_exact(16).map(|vertex| {
+            std::array::from_fn(|i| {
+                f32::from_le_bytes(vertex[i * 4..i * 4 + 4].try_into().unwrap())
+            })
+        });
+        dirs.zip(positions).collect()
+    }
+
+    #[test]
+    fn test_height_at_matches_the_finest_mesh_vertices() {
+        const RES: u32 = 16;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        height.par_map_pixels(|_, x, y, dir| 1000.0 + ((x * 7 + y * 13) % 5) as f64 + dir.x * 20.0);
+        // the finest level is dense enough to sample the full resolution layer
+        let (subdivide_initial, subdivide_level3) = (1, 5);
+        let chunks = generate_icosphere(
+            &height,
+            None,
+            1000.0,
+            subdivide_initial,
+            1,
+            2,
+            subdivide_level3,
+        );
+        let query = PlanetQuery::new(height, None);
+
+        chunks.iter().for_each(|chunk| {
+            let vertices =
+                level_vertices(chunk, subdivide_initial, subdivide_level3, &chunk.level3);
+            assert!(!vertices.is_empty());
+            vertices.iter().for_each(|(dir, position)| {
+                let expected = (*dir * query.height_at_dir(*dir) - chunk.center).as_vec3();
+                assert_eq!(*position, expected.to_array());
+            });
+        });
+    }
+
+    #[test]
+    fn test_height_at_dir_lod_matches_every_mesh_level() {
+        const RES: u32 = 64;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+        height.par_map_pixels(|_, x, y, dir| 1000.0 + ((x * 7 + y * 13) % 5) as f64 + dir.x * 20.0);
+        let (subdivide_initial, subdivide_levels) = (1, [1, 2, 3]);
+        let chunks = generate_icosphere(
+            &height,
+            None,
+            1000.0,
+            subdivide_initial,
+            subdivide_levels[0],
+            subdivide_levels[1],
+            subdivide_levels[2],
+        );
+        let query = PlanetQuery::new(height, None);
+
+        chunks.iter().for_each(|chunk| {
+            let levels = [&chunk.level1, &chunk.level2, &chunk.level3];
+            for (subdivide_level, data) in subdivide_levels.into_iter().zip(levels) {
+                let footprint =
+                    get_level_footprint(subdivide_initial as u32 + subdivide_level as u32);
+                let vertices = level_vertices(chunk, subdivide_initial, subdivide_level, data);
+                assert!(!vertices.is_empty());
+                vertices.iter().for_each(|(dir, position)| {
+                    let height = query.height_at_dir_lod(*dir, footprint);
+                    let expected = (*dir * height - chunk.center).as_vec3();
+                    assert_eq!(*position, expected.to_array());
+                });
+            }
+        });
+    }
+}
@@ -2,30 +2,96 @@ use glam::{DVec2, DVec3, DVec4};
 use rand_xoshiro::rand_core::{RngCore, SeedableRng};
 use rand_xoshiro::Xoshiro256StarStar;
 
+fn random_1d_hash(v: f64) -> u64 {
+    v.to_bits()
+}
+
+fn random_2d_hash(v: DVec2) -> u64 {
+    random_1d_to_1d(v.x).to_bits() ^ random_1d_to_1d(v.y + 1.234).to_bits()
+}
+
+fn random_3d_hash(v: DVec3) -> u64 {
+    random_1d_to_1d(v.x).to_bits()
+        ^ random_1d_to_1d(v.y + 1.234).to_bits()
+        ^ random_1d_to_1d(v.z + 2.345).to_bits()
+}
+
+fn random_4d_hash(v: DVec4) -> u64 {
+    random_1d_to_1d(v.x).to_bits()
+        ^ random_1d_to_1d(v.y + 1.234).to_bits()
+        ^ random_1d_to_1d(v.z + 2.345).to_bits()
+        ^ random_1d_to_1d(v.w + 3.456).to_bits()
+}
+
 fn random_1d_hasher(v: f64) -> Xoshiro256StarStar {
-    Xoshiro256StarStar::seed_from_u64(v.to_bits())
+    Xoshiro256StarStar::seed_from_u64(random_1d_hash(v))
 }
 
 fn random_2d_hasher(v: DVec2) -> Xoshiro256StarStar {
-    Xoshiro256StarStar::seed_from_u64(
-        random_1d_to_1d(v.x).to_bits() ^ random_1d_to_1d(v.y + 1.234).to_bits(),
-    )
+    Xoshiro256StarStar::seed_from_u64(random_2d_hash(v))
 }
 
 fn random_3d_hasher(v: DVec3) -> Xoshiro256StarStar {
-    Xoshiro256StarStar::seed_from_u64(
-        random_1d_to_1d(v.x).to_bits()
-            ^ random_1d_to_1d(v.y + 1.234).to_bits()
-            ^ random_1d_to_1d(v.z + 2.345).to_bits(),
-    )
+    Xoshiro256StarStar::seed_from_u64(random_3d_hash(v))
 }
 
 fn random_4d_hasher(v: DVec4) -> Xoshiro256StarStar {
-    Xoshiro256StarStar::seed_from_u64(
-        random_1d_to_1d(v.x).to_bits()
-            ^ random_1d_to_1d(v.y + 1.234).to_bits()
-            ^ random_1d_to_1d(v.z + 2.345).to_bits()
-            ^ random_1d_to_1d(v.z + 3.456).to_bits(),
+    Xoshiro256StarStar::seed_from_u64(random_4d_hash(v))
+}
+
+// Seeds for the separate stochastic stages, see derive_seed
+pub const SEED_STREAM_HEIGHT: u64 = 1;
+pub const SEED_STREAM_BIOME_MODIFIER: u64 = 2;
+pub const SEED_STREAM_BIOME_RANDOMIZER: u64 = 3;
+pub const SEED_STREAM_CRATERS: u64 = 4;
+pub const SEED_STREAM_EROSION: u64 = 5;
+pub const SEED_STREAM_NOISE_LAYERS: u64 = 6;
+pub const SEED_STREAM_HEIGHT_WARP: u64 = 7;
+pub const SEED_STREAM_BIOME_WARP: u64 = 8;
+
+fn splitmix64(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// The JSON seed is a float, every bit of it matters
+pub fn master_seed(seed: f64) -> u64 {
+    splitmix64(seed.to_bits())
+}
+
+// Sub-seed for a stage or an item inside of a stage, so a single master seed drives everything
+// while the stages stay independent from each other
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    splitmix64(seed ^ splitmix64(stream))
+}
+
+pub fn random_seed_to_array<const COUNT: usize>(seed: u64) -> [f64; COUNT] {
+    let mut s = Xoshiro256StarStar::seed_from_u64(seed);
+    [0.0; COUNT].map(|_| (s.next_u32() as f64) / (u32::MAX as f64))
+}
+
+pub fn random_2d_to_3d_seeded(v: DVec2, seed: u64) -> DVec3 {
+    let mut s = Xoshiro256StarStar::seed_from_u64(random_2d_hash(v) ^ seed);
+    DVec3::new(
+        (s.next_u32() as f64) / (u32::MAX as f64),
+        (s.next_u32() as f64) / (u32::MAX as f64),
+        (s.next_u32() as f64) / (u32::MAX as f64),
+    )
+}
+
+pub fn random_3d_to_1d_seeded(v: DVec3, seed: u64) -> f64 {
+    let mut s = Xoshiro256StarStar::seed_from_u64(random_3d_hash(v) ^ seed);
+    (s.next_u32() as f64) / (u32::MAX as f64)
+}
+
+pub fn random_3d_to_3d_seeded(v: DVec3, seed: u64) -> DVec3 {
+    let mut s = Xoshiro256StarStar::seed_from_u64(random_3d_hash(v) ^ seed);
+    DVec3::new(
+        (s.next_u32() as f64) / (u32::MAX as f64),
+        (s.next_u32() as f64) / (u32::MAX as f64),
+        (s.next_u32() as f64) / (u32::MAX as f64),
     )
 }
 
@@ -176,3 +242,18 @@ pub fn random_4d_to_array<const COUNT: usize>(v: DVec4) -> [f64; COUNT] {
     let mut s = random_4d_hasher(v);
     [0.0; COUNT].map(|_| (s.next_u32() as f64) / (u32::MAX as f64))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_4d_hash_uses_every_component() {
+        let base = DVec4::new(0.1, 0.2, 0.3, 0.4);
+        for axis in 0..4 {
+            let mut moved = base;
+            moved[axis] += 1.0;
+            assert_ne!(random_4d_to_1d(base), random_4d_to_1d(moved));
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
+use crate::generate_terrain::InterpolatedBiomeData;
+use glam::DVec3;
+
+const BOUNDS_FINEST_TILE_SIZE: usize = 8;
+const REFINE_ITERATIONS: u32 = 32;
+
+pub struct Hit {
+    pub position: DVec3,
+    pub normal: DVec3,
+    pub distance: f64,
+    pub biome: Option<InterpolatedBiomeData>,
+}
+
+struct HeightBoundsLevel {
+    tile_size: usize,
+    tiles_per_side: usize,
+    // min and max height per tile, per face, indexed by CubeMapFace::index
+    faces: [Vec<(f64, f64)>; 6],
+}
+
+// Min/max pyramid over the height layer, from tiles of BOUNDS_FINEST_TILE_SIZE pixels up to whole faces
+pub struct HeightBounds {
+    pub min: f64,
+    pub max: f64,
+    pixel_angle: f64,
+    levels: Vec<HeightBoundsLevel>,
+}
+
+impl HeightBounds {
+    pub fn new(height: &CubeMapDataLayer<f64>) -> HeightBounds {
+        let res = height.res as usize;
+        let faces = [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ];
+
+        let tiles_per_side = res.div_ceil(BOUNDS_FINEST_TILE_SIZE);
+        let finest = HeightBoundsLevel {
+            tile_size: BOUNDS_FINEST_TILE_SIZE,
+            tiles_per_side,
+            faces: faces.clone().map(|face| {
+                let mut tiles = vec![(f64::MAX, f64::MIN); tiles_per_side * tiles_per_side];
+                for y in 0..res {
+                    for x in 0..res {
+                        let value = height.get_pixel(&face, x, y);
+                        let tile = &mut tiles[(y / BOUNDS_FINEST_TILE_SIZE) * tiles_per_side
+                            + x / BOUNDS_FINEST_TILE_SIZE];
+                        tile.0 = tile.0.min(value);
+                        tile.1 = tile.1.max(value);
+                    }
+                }
+                tiles
+            }),
+        };
+
+        let mut levels = vec![finest];
+        while levels.last().unwrap().tiles_per_side > 1 {
+            let previous = levels.last().unwrap();
+            let tiles_per_side = previous.tiles_per_side.div_ceil(2);
+            let faces = faces.clone().map(|face| {
+                let previous_tiles = &previous.faces[face.index()];
+                let mut tiles = vec![(f64::MAX, f64::MIN); tiles_per_side * tiles_per_side];
+                for y in 0..previous.tiles_per_side {
+                    for x in 0..previous.tiles_per_side {
+                        let value = previous_tiles[y * previous.tiles_per_side + x];
+                        let tile = &mut tiles[(y / 2) * tiles_per_side + x / 2];
+                        tile.0 = tile.0.min(value.0);
+                        tile.1 = tile.1.max(value.1);
+                    }
+                }
+                tiles
+            });
+            levels.push(HeightBoundsLevel {
+                tile_size: previous.tile_size * 2,
+                tiles_per_side,
+                faces,
+            });
+        }
+
+        let whole_faces = &levels.last().unwrap().faces;
+        HeightBounds {
+            min: whole_faces.iter().map(|f| f[0].0).fold(f64::MAX, f64::min),
+            max: whole_faces.iter().map(|f| f[0].1).fold(f64::MIN, f64::max),
+            // pixels are the smallest in the face corners
+            pixel_angle: height
+                .get_smallest_pixel_distance()
+                .min(height.get_biggest_pixel_distance())
+                / 2.0_f64.sqrt(),
+            levels,
+        }
+    }
+
+    // Highest point the bilinear height can reach around a tile - the tile and its 8 neighbours,
+    // as the samples at the tile borders blend in pixels of the next tile. None if the
+    // neighbourhood leaves the face, the tiles do not know about the other faces
+    fn neighbourhood_max(
+        &self,
+        level: &HeightBoundsLevel,
+        face: &CubeMapFace,
+        x: usize,
+        y: usize,
+        res: usize,
+    ) -> Option<f64> {
+        let (tile_x, tile_y) = (x / level.tile_size, y / level.tile_size);
+        let inside = |tile: usize| tile > 0 && (tile + 2) * level.tile_size <= res;
+        if !inside(tile_x) || !inside(tile_y) {
+            return None;
+        }
+        let tiles = &level.faces[face.index()];
+        let mut top = f64::MIN;
+        for y in tile_y - 1..=tile_y + 1 {
+            for x in tile_x - 1..=tile_x + 1 {
+                top = top.max(tiles[y * level.tiles_per_side + x].1);
+            }
+        }
+        Some(top)
+    }
+
+    // How far a ray at the given point can safely advance - the coarsest tile whose
+    // neighbourhood stays below the point limits the step to half a tile, so the ray ends up
+    // in a neighbour at most. Half, as the pixels in the face corners are skewed
+    fn step(&self, height: &CubeMapDataLayer<f64>, point: DVec3, min_step: f64) -> f64 {
+        let radius = point.length();
+        let (face, x, y) = height.direction_to_pixel_coords(point / radius);
+        for level in self.levels.iter().rev() {
+            let Some(top) = self.neighbourhood_max(level, &face, x, y, height.res as usize) else {
+                continue;
+            };
+            if radius > top {
+                let extent = (level.tile_size / 2) as f64 * self.pixel_angle * top;
+                return (radius - top).min(extent).max(min_step);
+            }
+        }
+        min_step
+    }
+}
+
+// Distance along the ray to the first intersection with a sphere, None if it is missed entirely
+fn intersect_sphere(origin: DVec3, dir: DVec3, radius: f64) -> Option<(f64, f64)> {
+    let b = origin.dot(dir);
+    let c = origin.length_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    Some((-b - root, -b + root))
+}
+
+fn is_below_surface(height: &CubeMapDataLayer<f64>, point: DVec3) -> bool {
+    let radius = point.length();
+    radius <= height.get_bilinear(point / radius)
+}
+
+// Marches the ray through the shell between the lowest and the highest point of the terrain,
+// then refines the crossing by bisection on the bilinear height
+pub fn raycast(
+    height: &CubeMapDataLayer<f64>,
+    biome: Option<&CubeMapDataLayer<InterpolatedBiomeData>>,
+    bounds: &HeightBounds,
+    origin: DVec3,
+    dir: DVec3,
+    max_dist: f64,
+) -> Option<Hit> {
+    let dir = dir.normalize();
+
+    let (outer_enter, outer_exit) = intersect_sphere(origin, dir, bounds.max)?;
+    if outer_exit < 0.0 {
+        return None;
+    }
+    let start = outer_enter.max(0.0);
+    let mut end = outer_exit.min(max_dist);
+    // nothing is below the lowest point, so the ray must hit before entering that sphere
+    if let Some((inner_enter, _)) = intersect_sphere(origin, dir, bounds.min) {
+        if inner_enter >= start {
+            end = end.min(inner_enter);
+        }
+    }
+    if start > end {
+        return None;
+    }
+
+    let min_step = bounds.pixel_angle * bounds.min * 0.5;
+
+    let mut previous = start;
+    let mut current = start;
+    loop {
+        let point = origin + dir * current;
+        if is_below_surface(height, point) {
+            let mut above = previous;
+            let mut below = current;
+            for _ in 0..REFINE_ITERATIONS {
+                let middle = (above + below) * 0.5;
+                if is_below_surface(height, origin + dir * middle) {
+                    below = middle;
+                } else {
+                    above = middle;
+                }
+            }
+
+            let position = origin + dir * below;
+            let surface_dir = position.normalize();
+            return Some(Hit {
+                position,
+                normal: height
+                    .get_normal(surface_dir, height.get_pixel_distance_for_dir(surface_dir)),
+                distance: below,
+                biome: biome.map(|biome| biome.get(surface_dir)),
+            });
+        }
+        if current >= end {
+            return None;
+        }
+        previous = current;
+        current = (current + bounds.step(height, point, min_step)).min(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planet_query::PlanetQuery;
+
+    #[test]
+    fn test_raycast_sphere_with_bump() {
+        const RES: u32 = 64;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 1000.0);
+        // a 100 units high bump around +X
+        for y in 0..RES as usize {
+            for x in 0..RES as usize {
+                let dir = height.pixel_coords_to_direction(&CubeMapFace::PX, x, y);
+                let bump = (1.0 - dir.distance(DVec3::X) * 4.0).max(0.0);
+                height.set_pixel(&CubeMapFace::PX, x, y, 1000.0 + bump * 100.0);
+            }
+        }
+        let query = PlanetQuery::new(height, None);
+
+        let hit = query
+            .raycast(DVec3::new(0.0, 0.0, 5000.0), -DVec3::Z, 10000.0)
+            .unwrap();
+        assert!((hit.distance - 4000.0).abs() < 0.01);
+        assert!(hit.position.distance(DVec3::new(0.0, 0.0, 1000.0)) < 0.01);
+        assert!(hit.normal.dot(DVec3::Z) > 0.99);
+        assert!(hit.biome.is_none());
+
+        let hit = query
+            .raycast(DVec3::new(5000.0, 0.0, 0.0), -DVec3::X, 10000.0)
+            .unwrap();
+        let expected = query.height_at_dir(DVec3::X);
+        assert!(expected > 1090.0);
+        assert!((hit.position.length() - expected).abs() < 0.01);
+
+        // grazing the sphere from the side, just above the surface
+        assert!(query
+            .raycast(DVec3::new(-5000.0, 0.0, 1001.0), DVec3::X, 10000.0)
+            .is_none());
+        // too short
+        assert!(query
+            .raycast(DVec3::new(0.0, 0.0, 5000.0), -DVec3::Z, 3000.0)
+            .is_none());
+        // pointing away
+        assert!(query
+            .raycast(DVec3::new(0.0, 0.0, 5000.0), DVec3::Z, 10000.0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_raycast_grazing_one_pixel_spike() {
+        const RES: u32 = 64;
+        let mut height: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 1000.0);
+        height.set_pixel(&CubeMapFace::PX, 32, 32, 1100.0);
+        let spike = height.pixel_coords_to_direction(&CubeMapFace::PX, 32, 32);
+        let query = PlanetQuery::new(height, None);
+
+        // rays passing just over the flat ground, with their lowest point at the spike
+        for tangent in [DVec3::Y, DVec3::Z, DVec3::Y + DVec3::Z] {
+            let tangent = tangent.reject_from(spike).normalize();
+            for lowest in [1020.0, 1040.0, 1060.0, 1080.0] {
+                let origin = spike * lowest - tangent * 3000.0;
+                let hit = query.raycast(origin, tangent, 10000.0).unwrap();
+                let expected = query.height_at_dir(hit.position);
+                assert!((hit.position.length() - expected).abs() < 0.01);
+                assert!(hit.position.normalize().distance(spike) < 0.05);
+            }
+        }
+    }
+}
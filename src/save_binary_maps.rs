@@ -1,69 +1,190 @@
 use crate::cubemap_data::{CubeMapDataLayer, CubeMapFace};
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
+use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Error, ErrorKind, Read, Write};
 
 pub fn save_terrain_maps(
     output_dir: &str,
     terrain_min_height: f64,
     cube_map_height: &CubeMapDataLayer<f64>,
 ) {
-    let mutable_faces = [
-        (
-            CubeMapFace::PX,
-            cube_map_height.get_mutable_face(&CubeMapFace::PX),
-        ),
-        (
-            CubeMapFace::PY,
-            cube_map_height.get_mutable_face(&CubeMapFace::PY),
-        ),
-        (
-            CubeMapFace::PZ,
-            cube_map_height.get_mutable_face(&CubeMapFace::PZ),
-        ),
-        (
-            CubeMapFace::NX,
-            cube_map_height.get_mutable_face(&CubeMapFace::NX),
-        ),
-        (
-            CubeMapFace::NY,
-            cube_map_height.get_mutable_face(&CubeMapFace::NY),
-        ),
-        (
-            CubeMapFace::NZ,
-            cube_map_height.get_mutable_face(&CubeMapFace::NZ),
-        ),
-    ];
+    save_terrain_height_maps(
+        output_dir,
+        terrain_min_height,
+        cube_map_height.res,
+        |face, x, y| cube_map_height.get_pixel(face, x, y),
+    );
+}
 
+// Same as save_terrain_maps with the heights from height_pixel, for layers stored in other forms
+pub fn save_terrain_height_maps(
+    output_dir: &str,
+    terrain_min_height: f64,
+    res: u32,
+    height_pixel: impl Fn(&CubeMapFace, usize, usize) -> f64 + Sync,
+) {
     let mut metadata_file =
         File::create(output_dir.to_owned() + "/terrain_resolution.ini").expect("create failed");
     metadata_file
-        .write(format!("{}", cube_map_height.res).as_bytes())
+        .write_all(format!("{}", res).as_bytes())
         .expect("Write failed");
-    metadata_file.flush();
+    metadata_file.flush().expect("Write failed");
 
-    mutable_faces.into_par_iter().for_each(|face| {
-        println!(
-            "Saving binary height map face {}, res: {}",
-            face.0, cube_map_height.res
-        );
+    // what the heights are relative to, so load_terrain_maps can add it back on its own
+    let mut offset_file =
+        File::create(output_dir.to_owned() + "/terrain_offset.ini").expect("create failed");
+    offset_file
+        .write_all(format!("{}", terrain_min_height).as_bytes())
+        .expect("Write failed");
+    offset_file.flush().expect("Write failed");
+
+    let faces = [
+        CubeMapFace::PX,
+        CubeMapFace::PY,
+        CubeMapFace::PZ,
+        CubeMapFace::NX,
+        CubeMapFace::NY,
+        CubeMapFace::NZ,
+    ];
 
-        let face_data = face.1.lock().unwrap();
+    faces.into_par_iter().for_each(|face| {
+        println!("Saving binary height map face {}, res: {}", face, res);
 
         let mut file = brotli::CompressorWriter::new(
-            File::create(output_dir.to_owned() + format!("/terrain_{}.raw", face.0).as_str())
+            File::create(output_dir.to_owned() + format!("/terrain_{}.raw", face).as_str())
                 .expect("create failed"),
             40960,
             11,
             21,
         );
-        let res_usize = cube_map_height.res as usize;
-        for i in (0..res_usize * res_usize) {
-            file.write(&((face_data[i] - terrain_min_height) as f32).to_le_bytes())
-                .expect("Write failed");
+        let res_usize = res as usize;
+        for y in 0..res_usize {
+            for x in 0..res_usize {
+                let value = height_pixel(&face, x, y) - terrain_min_height;
+                file.write_all(&(value as f32).to_le_bytes())
+                    .expect("Write failed");
+            }
         }
 
         file.flush().unwrap();
     });
 }
+
+impl CubeMapDataLayer<f64> {
+    // Reads back what save_terrain_maps wrote, including the offset the heights are relative to
+    pub fn load_terrain_maps(input_dir: &str) -> std::io::Result<CubeMapDataLayer<f64>> {
+        let res_string = fs::read_to_string(input_dir.to_owned() + "/terrain_resolution.ini")?;
+        let res: u32 = res_string.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid terrain resolution: {}", res_string),
+            )
+        })?;
+        let offset_string = fs::read_to_string(input_dir.to_owned() + "/terrain_offset.ini")?;
+        let terrain_min_height: f64 = offset_string.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid terrain offset: {}", offset_string),
+            )
+        })?;
+        let res_usize = res as usize;
+
+        let mut cube_map_height = CubeMapDataLayer::new(res, 0.0);
+
+        for face in [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ] {
+            let mut bytes = vec![];
+            brotli::Decompressor::new(
+                File::open(input_dir.to_owned() + format!("/terrain_{}.raw", face).as_str())?,
+                40960,
+            )
+            .read_to_end(&mut bytes)?;
+
+            if bytes.len() != res_usize * res_usize * 4 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Terrain face {} has {} bytes, expected {} for res {}",
+                        face,
+                        bytes.len(),
+                        res_usize * res_usize * 4,
+                        res
+                    ),
+                ));
+            }
+
+            bytes.chunks_exact(4).enumerate().for_each(|(i, value)| {
+                cube_map_height.set_pixel(
+                    &face,
+                    i % res_usize,
+                    i / res_usize,
+                    f32::from_le_bytes(value.try_into().unwrap()) as f64 + terrain_min_height,
+                );
+            });
+        }
+
+        Ok(cube_map_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_terrain_maps_round_trip() {
+        const RES: u32 = 32;
+        let radius = 6378000.0;
+        let terrain_min_height = radius - 127000.0;
+        let mut cube_map: CubeMapDataLayer<f64> = CubeMapDataLayer::new(RES, 0.0);
+
+        let faces = [
+            CubeMapFace::PX,
+            CubeMapFace::PY,
+            CubeMapFace::PZ,
+            CubeMapFace::NX,
+            CubeMapFace::NY,
+            CubeMapFace::NZ,
+        ];
+
+        faces.iter().enumerate().for_each(|(face_index, face)| {
+            for y in 0..RES as usize {
+                for x in 0..RES as usize {
+                    let value = radius + (face_index * 10000 + y * 100 + x) as f64 * 1.25;
+                    cube_map.set_pixel(face, x, y, value);
+                }
+            }
+        });
+
+        let dir = env::temp_dir().join(format!("planetgen-terrain-maps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        save_terrain_maps(dir.to_str().unwrap(), terrain_min_height, &cube_map);
+
+        let loaded = CubeMapDataLayer::load_terrain_maps(dir.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.res, RES);
+        faces.iter().for_each(|face| {
+            for y in 0..RES as usize {
+                for x in 0..RES as usize {
+                    let expected = cube_map.get_pixel(face, x, y);
+                    let readback = loaded.get_pixel(face, x, y);
+                    // heights are saved as f32 relative to terrain_min_height
+                    assert!(
+                        (expected - readback).abs() < 0.01,
+                        "{face} {x} {y}: {expected} != {readback}"
+                    );
+                }
+            }
+        });
+    }
+}
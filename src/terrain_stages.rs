@@ -0,0 +1,352 @@
+use crate::craters::add_craters;
+use crate::cubemap_data::CubeMapDataLayer;
+use crate::erosion::erosion_run;
+use crate::generate_terrain::{
+    generate_biomes, generate_height, generate_splat_weights, TerrainLayers,
+};
+use crate::json_input::{
+    InputCelestialBodyDefinition, InputNoiseLayer, InputTerrain, InputTerrainStage,
+};
+use crate::math_util::mix;
+use crate::modifiers::{apply_color_modifiers, apply_height_modifiers};
+use crate::noise_layers::{generate_noise_layers, mask_layer_indices, NoiseLayers};
+use crate::random::{derive_seed, master_seed, SEED_STREAM_CRATERS, SEED_STREAM_EROSION};
+use glam::DVec3;
+
+pub struct StageContext<'a> {
+    pub input: &'a InputCelestialBodyDefinition,
+    pub terrain: &'a InputTerrain,
+}
+
+// A single step of the terrain generation, stages run in order on the same layers
+pub trait TerrainStage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers);
+}
+
+// The first pass of a stage uses the stream seed as is, so the default pipeline matches the
+// output from before the stages existed, repeated passes get their own seeds
+fn pass_seed(terrain: &InputTerrain, stream: u64, pass: u64) -> u64 {
+    let seed = derive_seed(master_seed(terrain.terrain_generation.seed), stream);
+    if pass == 0 {
+        seed
+    } else {
+        derive_seed(seed, pass)
+    }
+}
+
+struct HeightStage;
+
+impl TerrainStage for HeightStage {
+    fn name(&self) -> &'static str {
+        "height"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        generate_height(context.input, context.terrain, &mut layers.height);
+    }
+}
+
+struct NoiseLayersStage {
+    layers: Vec<InputNoiseLayer>,
+}
+
+impl TerrainStage for NoiseLayersStage {
+    fn name(&self) -> &'static str {
+        "noiseLayers"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        generate_noise_layers(
+            context.terrain,
+            &NoiseLayers::new(context.terrain, &self.layers),
+            &mut layers.height,
+        );
+    }
+}
+
+struct HeightModifiersStage;
+
+impl TerrainStage for HeightModifiersStage {
+    fn name(&self) -> &'static str {
+        "heightModifiers"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        apply_height_modifiers(context.terrain, &mut layers.height);
+    }
+}
+
+struct BiomesStage;
+
+impl TerrainStage for BiomesStage {
+    fn name(&self) -> &'static str {
+        "biomes"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        generate_biomes(
+            context.input,
+            context.terrain,
+            &layers.height,
+            &mut layers.biome,
+        );
+        if context.input.generator_config.export_splat_maps {
+            layers.splat = generate_splat_weights(context.terrain, &layers.height);
+        }
+    }
+}
+
+struct ColorModifiersStage;
+
+impl TerrainStage for ColorModifiersStage {
+    fn name(&self) -> &'static str {
+        "colorModifiers"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        apply_color_modifiers(context.terrain, &mut layers.biome);
+    }
+}
+
+struct CratersStage {
+    pass: u64,
+    count: Option<u32>,
+}
+
+impl TerrainStage for CratersStage {
+    fn name(&self) -> &'static str {
+        "craters"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        let terrain = context.terrain;
+        add_craters(
+            &mut layers.height,
+            &layers.biome,
+            terrain.radius,
+            pass_seed(terrain, SEED_STREAM_CRATERS, self.pass),
+            self.count
+                .unwrap_or(terrain.terrain_generation.craters_count),
+            context.input.generator_config.deterministic,
+        );
+    }
+}
+
+struct ErosionStage {
+    pass: u64,
+    iterations: Option<u16>,
+    droplets_count: Option<u16>,
+    droplet_velocity_coefficient: Option<f64>,
+    droplet_evaporation_coefficient: Option<f64>,
+}
+
+impl TerrainStage for ErosionStage {
+    fn name(&self) -> &'static str {
+        "erosion"
+    }
+
+    fn run(&self, context: &StageContext, layers: &mut TerrainLayers) {
+        let config = &context.input.generator_config;
+        erosion_run(
+            &mut layers.height,
+            &mut layers.biome,
+            self.iterations.unwrap_or(config.erosion_iterations),
+            self.droplets_count.unwrap_or(config.erosion_droplets_count),
+            context.terrain.radius,
+            pass_seed(context.terrain, SEED_STREAM_EROSION, self.pass),
+            self.droplet_velocity_coefficient
+                .unwrap_or(config.erosion_droplet_velocity_coefficient),
+            self.droplet_evaporation_coefficient
+                .unwrap_or(config.erosion_droplet_evaporation_coefficient),
+            config.deterministic,
+        );
+    }
+}
+
+// Moves every height towards the average of its 4 neighbours, by strength per iteration
+struct SmoothingStage {
+    iterations: u32,
+    strength: f64,
+}
+
+impl TerrainStage for SmoothingStage {
+    fn name(&self) -> &'static str {
+        "smoothing"
+    }
+
+    fn run(&self, _context: &StageContext, layers: &mut TerrainLayers) {
+        for _ in 0..self.iterations {
+            smooth_height(&mut layers.height, self.strength);
+        }
+    }
+}
+
+fn smooth_height(cube_map_height: &mut CubeMapDataLayer<f64>, strength: f64) {
+    // everything is read first, so the neighbours across face edges are not already smoothed
+    let mut smoothed =
+        CubeMapDataLayer::new_in(cube_map_height.res, 0.0, cube_map_height.storage());
+    smoothed.par_map_pixels(|face, x, y, dir| {
+        let distance = cube_map_height.get_pixel_distance_for_dir(dir);
+        let tangent = if dir.y.abs() < 0.99 {
+            DVec3::new(0.0, 1.0, 0.0).cross(dir).normalize()
+        } else {
+            DVec3::new(1.0, 0.0, 0.0).cross(dir).normalize()
+        } * distance;
+        let bitangent = dir.cross(tangent);
+
+        let average = [tangent, -tangent, bitangent, -bitangent]
+            .iter()
+            .map(|offset| cube_map_height.get_bilinear((dir + *offset).normalize()))
+            .sum::<f64>()
+            / 4.0;
+        mix(cube_map_height.get_pixel(face, x, y), average, strength)
+    });
+    *cube_map_height = smoothed;
+}
+
+// Same order the generator always had
+pub fn default_terrain_stages() -> Vec<InputTerrainStage> {
+    vec![
+        InputTerrainStage::Height,
+        InputTerrainStage::HeightModifiers,
+        InputTerrainStage::Biomes,
+        InputTerrainStage::Craters { count: None },
+        InputTerrainStage::Erosion {
+            iterations: None,
+            droplets_count: None,
+            droplet_velocity_coefficient: None,
+            droplet_evaporation_coefficient: None,
+        },
+        // remap biomes after erosion for some more realistic effect
+        InputTerrainStage::Biomes,
+        InputTerrainStage::ColorModifiers,
+    ]
+}
+
+// Errors on a definition that could only fail once its stage runs, before anything is generated
+pub fn build_terrain_stages(
+    stages: &[InputTerrainStage],
+) -> Result<Vec<Box<dyn TerrainStage>>, String> {
+    let mut crater_passes = 0;
+    let mut erosion_passes = 0;
+    stages
+        .iter()
+        .map(|stage| -> Result<Box<dyn TerrainStage>, String> {
+            Ok(match stage.clone() {
+                InputTerrainStage::Height => Box::new(HeightStage),
+                InputTerrainStage::NoiseLayers { layers } => {
+                    mask_layer_indices(&layers)?;
+                    Box::new(NoiseLayersStage { layers })
+                }
+                InputTerrainStage::HeightModifiers => Box::new(HeightModifiersStage),
+                InputTerrainStage::Biomes => Box::new(BiomesStage),
+                InputTerrainStage::ColorModifiers => Box::new(ColorModifiersStage),
+                InputTerrainStage::Craters { count } => {
+                    crater_passes += 1;
+                    Box::new(CratersStage {
+                        pass: crater_passes - 1,
+                        count,
+                    })
+                }
+                InputTerrainStage::Erosion {
+                    iterations,
+                    droplets_count,
+                    droplet_velocity_coefficient,
+                    droplet_evaporation_coefficient,
+                } => {
+                    erosion_passes += 1;
+                    Box::new(ErosionStage {
+                        pass: erosion_passes - 1,
+                        iterations,
+                        droplets_count,
+                        droplet_velocity_coefficient,
+                        droplet_evaporation_coefficient,
+                    })
+                }
+                InputTerrainStage::Smoothing {
+                    iterations,
+                    strength,
+                } => Box::new(SmoothingStage {
+                    iterations,
+                    strength,
+                }),
+            })
+        })
+        .collect()
+}
+
+pub fn run_terrain_stages(
+    context: &StageContext,
+    layers: &mut TerrainLayers,
+    stages: &[Box<dyn TerrainStage>],
+) {
+    for (index, stage) in stages.iter().enumerate() {
+        println!(
+            "Running terrain stage {}/{}: {}",
+            index + 1,
+            stages.len(),
+            stage.name()
+        );
+        stage.run(context, layers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cubemap_data::CubeMapFace;
+    use crate::generate_terrain::generate_terrain_layers;
+    use crate::json_input::parse_input_data;
+
+    #[test]
+    fn test_stages_from_json() {
+        let stages: Vec<InputTerrainStage> = serde_json::from_str(
+            r#"[
+                { "type": "height" },
+                { "type": "craters", "count": 2 },
+                { "type": "smoothing", "iterations": 2, "strength": 0.5 },
+                { "type": "craters" },
+                { "type": "biomes" }
+            ]"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = build_terrain_stages(&stages)
+            .unwrap()
+            .iter()
+            .map(|stage| stage.name())
+            .collect();
+        assert_eq!(
+            names,
+            ["height", "craters", "smoothing", "craters", "biomes"]
+        );
+
+        // smoothing only ever pulls the extremes in
+        let mut input = parse_input_data(include_str!("../test-input.json"));
+        input.generator_config.cube_map_resolution = 16;
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.stages = Some(vec![InputTerrainStage::Height]);
+        let rough = generate_terrain_layers(&input, input.terrain.as_ref().unwrap());
+        let terrain = input.terrain.as_mut().unwrap();
+        terrain.stages = Some(vec![
+            InputTerrainStage::Height,
+            InputTerrainStage::Smoothing {
+                iterations: 3,
+                strength: 1.0,
+            },
+        ]);
+        let smooth = generate_terrain_layers(&input, input.terrain.as_ref().unwrap());
+
+        let range = |layers: &TerrainLayers| {
+            let (mut min, mut max) = (f64::MAX, f64::MIN);
+            for face in [CubeMapFace::PX, CubeMapFace::NY, CubeMapFace::NZ] {
+                for value in layers.height.face(&face) {
+                    min = min.min(*value);
+                    max = max.max(*value);
+                }
+            }
+            max - min
+        };
+        assert!(range(&smooth) < range(&rough));
+    }
+}